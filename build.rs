@@ -0,0 +1,18 @@
+// Generates the placeholder of the symbol table embedded by src/backtrace.rs. The real table is
+// patched into the `.kernel_symbols` section of the linked kernel by symbols/process_symbols.py,
+// which is run by `cargo run` and `cargo test` as the runner.
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Must be the same as SYMBOL_TABLE_SIZE of src/backtrace.rs and symbols/process_symbols.py.
+const SYMBOL_TABLE_SIZE: usize = 0x80000;
+
+fn main() {
+    // a table without symbols
+    let mut table = vec![0u8; SYMBOL_TABLE_SIZE];
+    table[..4].copy_from_slice(b"KSYM");
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out_dir.join("symbols.bin"), table).unwrap();
+    println!("cargo:rerun-if-changed=build.rs");
+}
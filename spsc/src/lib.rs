@@ -0,0 +1,275 @@
+//! Lock-free ring buffer with a single producer and a single consumer, for delivering data from
+//! interrupt handlers to tasks.
+#![no_std]
+
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Lock-free ring buffer with a single producer and a single consumer.
+///
+/// Unlike `FIFO` behind a `spin::Mutex`, pushing and popping never lock, so an interrupt handler
+/// can push while a task is popping without `cli`.
+/// Obtain the `Producer` and the `Consumer` with `split`.
+pub struct SpscRing<T> {
+    /// One slot is always kept empty to distinguish a full buffer from an empty one.
+    buf: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Index of the slot to be written next. Only the producer modifies this.
+    head: AtomicUsize,
+    /// Index of the slot to be read next. Only the consumer modifies this.
+    tail: AtomicUsize,
+    /// The task woken up when data is pushed, or `NO_TASK`.
+    task: AtomicUsize,
+    /// Called with `task` when data is pushed, e.g. `task::wake` of the kernel.
+    wake: fn(usize),
+}
+
+const NO_TASK: usize = usize::MAX;
+
+// Slots are accessed by at most one producer and one consumer, which is guaranteed by `split`.
+unsafe impl<T: Send> Sync for SpscRing<T> {}
+
+impl<T> SpscRing<T> {
+    /// Create a ring holding up to `capacity` data, which calls `wake` with the task set by
+    /// `set_task` whenever data is pushed.
+    pub fn new(capacity: usize, wake: fn(usize)) -> Self {
+        let mut buf = Vec::with_capacity(capacity + 1);
+        buf.resize_with(capacity + 1, || UnsafeCell::new(MaybeUninit::uninit()));
+        Self {
+            buf: buf.into_boxed_slice(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            task: AtomicUsize::new(NO_TASK),
+            wake,
+        }
+    }
+    /// Set the task which consumes this ring. The task is woken up whenever data is pushed.
+    pub fn set_task(&self, task: Option<usize>) {
+        self.task.store(task.unwrap_or(NO_TASK), Ordering::Release);
+    }
+    /// Split into the producer and the consumer.
+    /// For interrupt handlers, leak the ring (e.g. with `Box::leak`) to obtain `'static` halves.
+    pub fn split(&mut self) -> (Producer<'_, T>, Consumer<'_, T>) {
+        let ring = &*self;
+        (Producer { ring }, Consumer { ring })
+    }
+    pub fn capacity(&self) -> usize {
+        self.buf.len() - 1
+    }
+    /// The number of data in the buffer.
+    pub fn status(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        (head + self.buf.len() - tail) % self.buf.len()
+    }
+    fn next(&self, index: usize) -> usize {
+        (index + 1) % self.buf.len()
+    }
+}
+
+impl<T> Drop for SpscRing<T> {
+    fn drop(&mut self) {
+        let mut tail = *self.tail.get_mut();
+        let head = *self.head.get_mut();
+        while tail != head {
+            unsafe {
+                core::ptr::drop_in_place((*self.buf[tail].get()).as_mut_ptr());
+            }
+            tail = self.next(tail);
+        }
+    }
+}
+
+/// The pushing half of `SpscRing`.
+pub struct Producer<'a, T> {
+    ring: &'a SpscRing<T>,
+}
+
+impl<'a, T> Producer<'a, T> {
+    /// Push data to the ring. Returns the data back if the ring is full.
+    pub fn push(&mut self, data: T) -> Result<(), T> {
+        let ring = self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        let next = ring.next(head);
+        if next == ring.tail.load(Ordering::Acquire) {
+            return Err(data);
+        }
+        unsafe {
+            (*ring.buf[head].get()).as_mut_ptr().write(data);
+        }
+        ring.head.store(next, Ordering::Release);
+        let task = ring.task.load(Ordering::Acquire);
+        if task != NO_TASK {
+            (ring.wake)(task);
+        }
+        Ok(())
+    }
+    pub fn status(&self) -> usize {
+        self.ring.status()
+    }
+}
+
+/// The popping half of `SpscRing`.
+pub struct Consumer<'a, T> {
+    ring: &'a SpscRing<T>,
+}
+
+impl<'a, T> Consumer<'a, T> {
+    /// Pop the oldest data from the ring, or None if the ring is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        let ring = self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        if tail == ring.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let data = unsafe { (*ring.buf[tail].get()).as_ptr().read() };
+        ring.tail.store(ring.next(tail), Ordering::Release);
+        Some(data)
+    }
+    /// Set the task which is woken up whenever data is pushed, usually the owner of this consumer.
+    pub fn set_task(&self, task: Option<usize>) {
+        self.ring.set_task(task);
+    }
+    pub fn status(&self) -> usize {
+        self.ring.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn no_wake(_task: usize) {}
+
+    #[test]
+    fn wraparound() {
+        let mut ring = SpscRing::new(3, no_wake);
+        let (mut producer, mut consumer) = ring.split();
+        for i in 0..100 {
+            producer.push(i).unwrap();
+            producer.push(i + 1).unwrap();
+            assert_eq!(consumer.pop(), Some(i));
+            assert_eq!(consumer.pop(), Some(i + 1));
+            assert_eq!(consumer.pop(), None);
+        }
+    }
+
+    #[test]
+    fn full() {
+        let mut ring = SpscRing::new(2, no_wake);
+        let (mut producer, mut consumer) = ring.split();
+        producer.push(1).unwrap();
+        producer.push(2).unwrap();
+        assert_eq!(producer.push(3), Err(3));
+        assert_eq!(producer.status(), 2);
+        assert_eq!(consumer.pop(), Some(1));
+        producer.push(3).unwrap();
+        assert_eq!(consumer.pop(), Some(2));
+        assert_eq!(consumer.pop(), Some(3));
+        assert_eq!(consumer.status(), 0);
+    }
+
+    #[test]
+    fn wake() {
+        static WOKEN: AtomicUsize = AtomicUsize::new(0);
+        fn wake(task: usize) {
+            assert_eq!(task, 7);
+            WOKEN.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut ring = SpscRing::new(2, wake);
+        let (mut producer, consumer) = ring.split();
+        producer.push(1).unwrap();
+        assert_eq!(WOKEN.load(Ordering::Relaxed), 0);
+        consumer.set_task(Some(7));
+        producer.push(2).unwrap();
+        // nothing is pushed to the full ring, so nobody is woken up
+        producer.push(3).unwrap_err();
+        assert_eq!(WOKEN.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn drop_remaining() {
+        let data = Arc::new(());
+        let mut ring = SpscRing::new(4, no_wake);
+        {
+            let (mut producer, mut consumer) = ring.split();
+            for _ in 0..3 {
+                producer.push(data.clone()).unwrap();
+            }
+            consumer.pop().unwrap();
+        }
+        assert_eq!(Arc::strong_count(&data), 3);
+        drop(ring);
+        assert_eq!(Arc::strong_count(&data), 1);
+    }
+
+    #[test]
+    fn concurrent_producer_and_consumer() {
+        const COUNT: u64 = 1_000_000;
+        let (mut producer, mut consumer) = Box::leak(Box::new(SpscRing::new(4, no_wake))).split();
+        let producer = thread::spawn(move || {
+            for i in 0..COUNT {
+                let mut data = i;
+                // the small ring is full most of the time, so this retries many times
+                while let Err(rejected) = producer.push(data) {
+                    data = rejected;
+                    thread::yield_now();
+                }
+            }
+        });
+        // the data arrive in order and nothing is lost
+        let mut expected = 0;
+        while expected < COUNT {
+            match consumer.pop() {
+                Some(data) => {
+                    assert_eq!(data, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        producer.join().unwrap();
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn concurrent_wake() {
+        // the consumer sleeps until it is woken up, like a task of the kernel
+        static PENDING: AtomicBool = AtomicBool::new(false);
+        fn wake(_task: usize) {
+            PENDING.store(true, Ordering::Release);
+        }
+        const COUNT: u32 = 100_000;
+        let ring = Box::leak(Box::new(SpscRing::new(16, wake)));
+        ring.set_task(Some(0));
+        let (mut producer, mut consumer) = ring.split();
+        let producer = thread::spawn(move || {
+            for i in 0..COUNT {
+                while producer.push(i).is_err() {
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < COUNT {
+            PENDING.store(false, Ordering::Release);
+            while let Some(data) = consumer.pop() {
+                assert_eq!(data, expected);
+                expected += 1;
+            }
+            // a push after the ring was emptied sets `PENDING` again, so it is never missed
+            while expected < COUNT && consumer.status() == 0 && !PENDING.load(Ordering::Acquire) {
+                thread::yield_now();
+            }
+        }
+        producer.join().unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+use core::ptr::read_unaligned;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Size of the header common to all system description tables.
+const SDT_HEADER_SIZE: u64 = 36;
+
+/// Find the system description table with `signature`, e.g. `b"HPET"`, and return its physical
+/// address. The RSDT, or the XSDT if available, is searched.
+///
+/// This function is unsafe because the caller must guarantee that the complete physical memory is
+/// mapped to virtual memory at `physical_memory_offset`.
+pub unsafe fn find_table(
+    physical_memory_offset: VirtAddr,
+    signature: &[u8; 4],
+) -> Option<PhysAddr> {
+    let rsdp = find_rsdp(physical_memory_offset)?;
+    let revision = read::<u8>(physical_memory_offset, rsdp + 15);
+    let xsdt = read::<u64>(physical_memory_offset, rsdp + 24);
+    let (root, entry_size) = if revision >= 2 && xsdt != 0 {
+        (xsdt, 8)
+    } else {
+        (read::<u32>(physical_memory_offset, rsdp + 16) as u64, 4)
+    };
+
+    let length = read::<u32>(physical_memory_offset, root + 4) as u64;
+    let entries = (length - SDT_HEADER_SIZE) / entry_size;
+    (0..entries)
+        .map(|i| {
+            let entry = root + SDT_HEADER_SIZE + i * entry_size;
+            if entry_size == 8 {
+                read::<u64>(physical_memory_offset, entry)
+            } else {
+                read::<u32>(physical_memory_offset, entry) as u64
+            }
+        })
+        .find(|&table| read::<[u8; 4]>(physical_memory_offset, table) == *signature)
+        .map(PhysAddr::new)
+}
+
+/// The routing of an ISA IRQ to the I/O APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsaIrq {
+    /// The global system interrupt the IRQ is connected to.
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// The I/O APIC and the interrupt source overrides found in the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    /// Physical address of the registers of the I/O APIC.
+    pub ioapic_address: PhysAddr,
+    /// The global system interrupt of the input 0 of the I/O APIC.
+    pub ioapic_gsi_base: u32,
+    /// Interrupt source overrides indexed by ISA IRQ.
+    overrides: [Option<IsaIrq>; 16],
+}
+
+impl Madt {
+    /// The routing of ISA IRQ `irq`. Without an interrupt source override, the IRQ is connected to
+    /// the global system interrupt of the same number, edge-triggered and active high.
+    pub fn isa_irq(&self, irq: u8) -> IsaIrq {
+        self.overrides[irq as usize].unwrap_or(IsaIrq {
+            gsi: irq as u32,
+            active_low: false,
+            level_triggered: false,
+        })
+    }
+}
+
+/// Parse the MADT to find the first I/O APIC and the interrupt source overrides of ISA IRQs.
+/// Returns None if there is no MADT or the MADT has no I/O APIC.
+///
+/// This function is unsafe because the caller must guarantee that the complete physical memory is
+/// mapped to virtual memory at `physical_memory_offset`.
+pub unsafe fn parse_madt(physical_memory_offset: VirtAddr) -> Option<Madt> {
+    let table = find_table(physical_memory_offset, b"APIC")?.as_u64();
+    let length = read::<u32>(physical_memory_offset, table + 4) as u64;
+    let mut ioapic = None;
+    let mut overrides = [None; 16];
+    // the entries follow the address of the local APIC and the flags
+    let mut entry = table + SDT_HEADER_SIZE + 8;
+    while entry + 2 <= table + length {
+        let entry_type = read::<u8>(physical_memory_offset, entry);
+        let entry_length = read::<u8>(physical_memory_offset, entry + 1) as u64;
+        if entry_length < 2 {
+            break;
+        }
+        match entry_type {
+            // I/O APIC
+            1 if ioapic.is_none() => {
+                let address = read::<u32>(physical_memory_offset, entry + 4) as u64;
+                let gsi_base = read::<u32>(physical_memory_offset, entry + 8);
+                ioapic = Some((PhysAddr::new(address), gsi_base));
+            }
+            // interrupt source override
+            2 => {
+                let bus = read::<u8>(physical_memory_offset, entry + 2);
+                let source = read::<u8>(physical_memory_offset, entry + 3) as usize;
+                let gsi = read::<u32>(physical_memory_offset, entry + 4);
+                let flags = read::<u16>(physical_memory_offset, entry + 8);
+                // bus 0 is ISA. Polarity and trigger mode of 0b00 conform to the bus, which
+                // means active high and edge-triggered for ISA.
+                if bus == 0 && source < overrides.len() {
+                    overrides[source] = Some(IsaIrq {
+                        gsi,
+                        active_low: flags & 0b11 == 0b11,
+                        level_triggered: flags >> 2 & 0b11 == 0b11,
+                    });
+                }
+            }
+            _ => {}
+        }
+        entry += entry_length;
+    }
+    let (ioapic_address, ioapic_gsi_base) = ioapic?;
+    Some(Madt {
+        ioapic_address,
+        ioapic_gsi_base,
+        overrides,
+    })
+}
+
+/// Search the RSDP in the first 1KiB of the EBDA and in the BIOS area (0xe0000 - 0xfffff).
+unsafe fn find_rsdp(physical_memory_offset: VirtAddr) -> Option<u64> {
+    let ebda = (read::<u16>(physical_memory_offset, 0x40e) as u64) << 4;
+    (ebda..ebda + 0x400)
+        .step_by(16)
+        .chain((0xe0000..0x100000).step_by(16))
+        .find(|&address| {
+            read::<[u8; 8]>(physical_memory_offset, address) == *b"RSD PTR "
+                && (0..20).fold(0u8, |sum, i| {
+                    sum.wrapping_add(read::<u8>(physical_memory_offset, address + i))
+                }) == 0
+        })
+}
+
+/// Read `T` at the physical address `address`.
+pub(crate) unsafe fn read<T: Copy>(physical_memory_offset: VirtAddr, address: u64) -> T {
+    read_unaligned((physical_memory_offset + address).as_ptr())
+}
+
+#[test_case]
+fn test_parse_madt() {
+    let physical_memory_offset = crate::memory::physical_memory_offset();
+    let madt = unsafe { parse_madt(physical_memory_offset) }.unwrap();
+    // QEMU has the I/O APIC at the standard address, and connects PIT to the input 2
+    assert_eq!(madt.ioapic_address.as_u64(), 0xfec0_0000);
+    assert_eq!(madt.isa_irq(0).gsi, 2);
+    assert_eq!(
+        madt.isa_irq(1),
+        IsaIrq {
+            gsi: 1,
+            active_low: false,
+            level_triggered: false
+        }
+    );
+}
@@ -1,22 +1,27 @@
+use crate::sync::{IrqMutex, IrqMutexGuard};
+use alloc::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB,
     },
     VirtAddr,
 };
 
-/// a wrapper around spin::Mutex to permit trait immplmentations
+/// a wrapper around IrqMutex to permit trait immplmentations
+/// Every task allocates memory, so the allocator is locked with interrupts disabled.
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqMutex<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Self {
-            inner: spin::Mutex::new(inner),
+            inner: IrqMutex::new(inner),
         }
     }
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqMutexGuard<A> {
         self.inner.lock()
     }
 }
@@ -28,6 +33,43 @@ fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
 
+/// Counters of allocations, e.g. of a size class.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    /// The number of allocations.
+    pub allocs: u64,
+    /// The number of deallocations.
+    pub frees: u64,
+    /// The number of allocated blocks not freed yet.
+    pub live: usize,
+    /// The number of requested bytes in the live blocks.
+    pub bytes: usize,
+}
+
+impl ClassStats {
+    pub const fn new() -> Self {
+        Self {
+            allocs: 0,
+            frees: 0,
+            live: 0,
+            bytes: 0,
+        }
+    }
+    fn record_alloc(&mut self, size: usize) {
+        self.allocs += 1;
+        self.live += 1;
+        self.bytes += size;
+    }
+    fn record_free(&mut self, size: usize) {
+        self.frees += 1;
+        self.live -= 1;
+        self.bytes -= size;
+    }
+}
+
+pub mod buddy;
+/// tracking live allocations with their call-sites
+pub mod debug;
 pub mod fixed_size_block;
 
 pub mod bump {
@@ -38,6 +80,7 @@ pub mod bump {
         heap_end: usize,
         next: usize,
         allocations: usize,
+        stats: ClassStats,
     }
 
     impl BumpAllocator {
@@ -47,15 +90,25 @@ pub mod bump {
                 heap_end: 0,
                 next: 0,
                 allocations: 0,
+                stats: ClassStats::new(),
             }
         }
-        pub fn init(&mut self, heap_start: usize, heap_size: usize) {
+        pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
             self.heap_start = heap_start;
             self.heap_end = heap_start + heap_size;
             self.next = heap_start;
         }
+        /// Counters of all allocations.
+        pub fn stats(&self) -> ClassStats {
+            self.stats
+        }
+        /// The number of bytes consumed from the heap, which are reclaimed only when all
+        /// allocations are freed.
+        pub fn used(&self) -> usize {
+            self.next - self.heap_start
+        }
     }
-    use super::{align_up, Locked};
+    use super::{align_up, debug, ClassStats, Locked};
 
     unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
@@ -70,11 +123,15 @@ pub mod bump {
             } else {
                 bump.next = alloc_end;
                 bump.allocations += 1;
+                bump.stats.record_alloc(layout.size());
+                debug::record_alloc(alloc_start, layout.size());
                 alloc_start as *mut u8
             }
         }
-        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
             let mut bump = self.lock();
+            bump.stats.record_free(layout.size());
+            debug::record_dealloc(ptr as usize);
             bump.allocations -= 1;
             if bump.allocations == 0 {
                 bump.next = bump.heap_start;
@@ -84,15 +141,98 @@ pub mod bump {
 }
 
 pub const HEAP_START: usize = 0x4444_4444_0000;
+/// Size of the heap mapped by `init_heap`. The heap grows on demand up to `heap_limit()`.
 pub const HEAP_SIZE: usize = 1000 * 1024;
+/// Default of the maximum size of the heap.
+pub const HEAP_MAX_SIZE: usize = 32 * 1024 * 1024;
+/// The minimum size by which the heap grows at once, not to map pages on every allocation.
+const HEAP_GROW_SIZE: usize = 64 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+/// The maximum size of the heap.
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Change the maximum size of the heap. The heap never shrinks even if it is already larger.
+pub fn set_heap_limit(size: usize) {
+    HEAP_LIMIT.store(size, Ordering::Relaxed);
+}
+
+/// Maps memory at `heap_end`, the current end of a heap, so that an allocation of `layout` can
+/// succeed, and returns the number of bytes mapped. Given to allocators with `set_grow`.
+pub type GrowFn = fn(heap_end: usize, layout: &Layout) -> usize;
+
+/// Map pages at `heap_end`, the current end of the kernel heap, so that an allocation of `layout`
+/// can succeed. Returns the number of bytes mapped, which may be 0 if the heap reached the limit, no
+/// frame is left, memory is not initialized by `memory::init_global` yet, or the page table is
+/// locked, e.g. by the caller of `memory::with_mapper` allocating memory.
+/// Called by the global allocator, which must extend the heap by the returned size.
+#[cfg_attr(feature = "bump_allocator", allow(dead_code))]
+fn grow_heap(heap_end: usize, layout: &Layout) -> usize {
+    let heap_limit_end = HEAP_START.saturating_add(heap_limit());
+    // the free space at the end of the heap may be smaller than `layout`, but the whole layout
+    // is mapped newly for simplicity
+    let required = align_up(
+        layout
+            .size()
+            .saturating_add(layout.align())
+            .max(HEAP_GROW_SIZE),
+        PAGE_SIZE,
+    );
+    let grow_end = heap_end.saturating_add(required).min(heap_limit_end);
+    if grow_end <= heap_end || grow_end - heap_end < layout.size() {
+        return 0;
+    }
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(heap_end as u64));
+    let last = Page::containing_address(VirtAddr::new(grow_end as u64 - 1));
+    crate::memory::try_with_mapper(|mapper, frame_allocator| {
+        let mut mapped = 0;
+        for page in Page::range_inclusive(first, last) {
+            let frame = match frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => break,
+            };
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    break;
+                }
+            }
+            mapped += PAGE_SIZE;
+        }
+        mapped
+    })
+    .unwrap_or(0)
+}
+
+// The global allocator is selected by the cargo features `bump_allocator` and
+// `buddy_allocator`. The fixed-size block allocator is used without them.
+#[cfg(all(feature = "bump_allocator", feature = "buddy_allocator"))]
+compile_error!("the features `bump_allocator` and `buddy_allocator` are mutually exclusive");
+#[cfg(feature = "buddy_allocator")]
+use buddy::{BuddyAllocator as GlobalAllocator, Stats};
+#[cfg(all(feature = "bump_allocator", not(feature = "buddy_allocator")))]
+use bump::BumpAllocator as GlobalAllocator;
+#[cfg(not(any(feature = "bump_allocator", feature = "buddy_allocator")))]
+use fixed_size_block::{FixedSizeBlockAllocator as GlobalAllocator, Stats};
+#[cfg(all(feature = "bump_allocator", not(feature = "buddy_allocator")))]
+use ClassStats as Stats;
 
-use fixed_size_block::FixedSizeBlockAllocator;
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
-// static ALLOCATOR: Locked<bump::BumpAllocator> = Locked::new(bump::BumpAllocator::new());
+static ALLOCATOR: Locked<GlobalAllocator> = Locked::new(GlobalAllocator::new());
+
+/// Statistics of the global allocator.
+pub fn stats() -> Stats {
+    ALLOCATOR.lock().stats()
+}
 
 #[alloc_error_handler]
-fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+fn alloc_error_handler(layout: Layout) -> ! {
     panic!("allocation error: {:?}", layout)
 }
 
@@ -119,5 +259,24 @@ pub fn init_heap(
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
     }
+    // the bump allocator doesn't grow
+    #[cfg(not(feature = "bump_allocator"))]
+    ALLOCATOR.lock().set_grow(grow_heap);
     Ok(())
 }
+
+// the bump allocator doesn't grow
+#[cfg(not(feature = "bump_allocator"))]
+#[test_case]
+fn test_heap_growth() {
+    use alloc::vec::Vec;
+    // larger than the initial heap
+    let mut vec = Vec::<u8>::with_capacity(HEAP_SIZE * 2);
+    vec.resize(HEAP_SIZE * 2, 42);
+    assert!(vec.iter().all(|&x| x == 42));
+    drop(vec);
+
+    // allocations beyond the limit fail instead of mapping more pages
+    let layout = Layout::from_size_align(heap_limit() + PAGE_SIZE, 8).unwrap();
+    assert!(unsafe { alloc::alloc::alloc(layout) }.is_null());
+}
@@ -0,0 +1,292 @@
+use super::{debug, ClassStats, GrowFn, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{fmt, mem, ptr};
+
+// Every block has a size of a power of two, and is aligned to its size. A free block of order n
+// is split into two halves of order n - 1, which are buddies of each other. When a block is freed
+// and its buddy is free too, they are merged into the block of the upper order again.
+
+/// Size of the blocks of order 0.
+const MIN_BLOCK_SIZE: usize = 16;
+/// The number of orders. The largest block is 2 GiB.
+const ORDERS: usize = 28;
+
+/// A free block, linked to the free list of its order.
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+fn block_size(order: usize) -> usize {
+    MIN_BLOCK_SIZE << order
+}
+
+/// The order of the smallest block which can hold `layout`.
+fn order_of(layout: &Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(MIN_BLOCK_SIZE)
+        .checked_next_power_of_two()?;
+    let order = (size / MIN_BLOCK_SIZE).trailing_zeros() as usize;
+    if order < ORDERS {
+        Some(order)
+    } else {
+        None
+    }
+}
+
+pub struct BuddyAllocator {
+    free_lists: [*mut FreeBlock; ORDERS],
+    /// The number of blocks in each free list.
+    free_blocks: [usize; ORDERS],
+    heap_start: usize,
+    heap_end: usize,
+    stats: ClassStats,
+    /// Extends the heap when no block is free. None for allocators which don't grow.
+    grow: Option<GrowFn>,
+}
+
+// The free blocks are owned by the allocator and only accessed through it.
+unsafe impl Send for BuddyAllocator {}
+
+/// Snapshot of the statistics of `BuddyAllocator`.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// Counters of all allocations.
+    pub allocations: ClassStats,
+    /// Free blocks of each order.
+    pub free_blocks: [usize; ORDERS],
+    pub heap_size: usize,
+    pub heap_free: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "allocs: {}, frees: {}, live: {}, bytes: {}",
+            self.allocations.allocs,
+            self.allocations.frees,
+            self.allocations.live,
+            self.allocations.bytes
+        )?;
+        for (order, &count) in self.free_blocks.iter().enumerate() {
+            if count > 0 {
+                writeln!(f, "{:>10} x {}", block_size(order), count)?;
+            }
+        }
+        write!(
+            f,
+            "heap: {} of {} bytes free",
+            self.heap_free, self.heap_size
+        )
+    }
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            free_lists: [ptr::null_mut(); ORDERS],
+            free_blocks: [0; ORDERS],
+            heap_start: 0,
+            heap_end: 0,
+            stats: ClassStats::new(),
+            grow: None,
+        }
+    }
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        // the heap must not run past the given bounds even if `heap_start` is unaligned
+        let heap_end = heap_start.saturating_add(heap_size);
+        let heap_start = super::align_up(heap_start, MIN_BLOCK_SIZE);
+        self.heap_start = heap_start;
+        self.heap_end = heap_start;
+        self.add_region(heap_end.saturating_sub(heap_start));
+    }
+    /// Let the heap grow with `grow` when no block is free. The heap doesn't grow without this.
+    pub fn set_grow(&mut self, grow: GrowFn) {
+        self.grow = Some(grow);
+    }
+    /// Add the memory of `size` bytes at the end of the heap as free blocks, each of which is
+    /// the largest one aligned at its address.
+    unsafe fn add_region(&mut self, size: usize) {
+        let end = (self.heap_end + size) & !(MIN_BLOCK_SIZE - 1);
+        let mut address = self.heap_end;
+        while address < end {
+            let mut order = ORDERS - 1;
+            while address % block_size(order) != 0 || address + block_size(order) > end {
+                order -= 1;
+            }
+            self.push(order, address);
+            address += block_size(order);
+        }
+        self.heap_end = end;
+    }
+    unsafe fn push(&mut self, order: usize, address: usize) {
+        let block = address as *mut FreeBlock;
+        block.write(FreeBlock {
+            next: self.free_lists[order],
+        });
+        self.free_lists[order] = block;
+        self.free_blocks[order] += 1;
+    }
+    unsafe fn pop(&mut self, order: usize) -> Option<usize> {
+        let block = self.free_lists[order];
+        if block.is_null() {
+            return None;
+        }
+        self.free_lists[order] = (*block).next;
+        self.free_blocks[order] -= 1;
+        Some(block as usize)
+    }
+    /// Remove the block at `address` from the free list of `order` if it is there.
+    unsafe fn remove(&mut self, order: usize, address: usize) -> bool {
+        let mut link: *mut *mut FreeBlock = &mut self.free_lists[order];
+        while !(*link).is_null() {
+            if *link as usize == address {
+                *link = (**link).next;
+                self.free_blocks[order] -= 1;
+                return true;
+            }
+            link = &mut (**link).next;
+        }
+        false
+    }
+    /// Take a block of `order`, splitting a larger block if there is no free block of the order.
+    unsafe fn take_block(&mut self, order: usize) -> Option<usize> {
+        let larger = (order..ORDERS).find(|&larger| !self.free_lists[larger].is_null())?;
+        let address = self.pop(larger)?;
+        // keep the lower half and free the upper half, until the block has the required order
+        for lower in (order..larger).rev() {
+            self.push(lower, address + block_size(lower));
+        }
+        Some(address)
+    }
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let order = match order_of(&layout) {
+            Some(order) => order,
+            None => return ptr::null_mut(),
+        };
+        if let Some(address) = unsafe { self.take_block(order) } {
+            return address as *mut u8;
+        }
+        // grow the heap and try again
+        let block_layout = Layout::from_size_align(block_size(order), block_size(order)).unwrap();
+        let grown = self
+            .grow
+            .map_or(0, |grow| grow(self.heap_end, &block_layout));
+        if grown == 0 {
+            return ptr::null_mut();
+        }
+        unsafe {
+            self.add_region(grown);
+            self.take_block(order)
+                .map_or(ptr::null_mut(), |address| address as *mut u8)
+        }
+    }
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let mut order = order_of(&layout).unwrap();
+        let mut address = ptr as usize;
+        // merge with the buddy as long as it is free
+        while order + 1 < ORDERS {
+            let buddy = address ^ block_size(order);
+            if buddy < self.heap_start
+                || buddy + block_size(order) > self.heap_end
+                || !self.remove(order, buddy)
+            {
+                break;
+            }
+            address = address.min(buddy);
+            order += 1;
+        }
+        self.push(order, address);
+    }
+    /// Counters of allocations and the free blocks.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            allocations: self.stats,
+            free_blocks: self.free_blocks,
+            heap_size: self.heap_end - self.heap_start,
+            heap_free: (0..ORDERS)
+                .map(|order| self.free_blocks[order] * block_size(order))
+                .sum(),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let ptr = allocator.alloc(layout);
+        if !ptr.is_null() {
+            allocator.stats.record_alloc(layout.size());
+            debug::record_alloc(ptr as usize, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.stats.record_free(layout.size());
+        debug::record_dealloc(ptr as usize);
+        allocator.dealloc(ptr, layout);
+    }
+}
+
+#[test_case]
+fn test_buddy() {
+    const HEAP_SIZE: usize = 4096 * 4;
+    // aligned to its size, so that the whole heap is one block
+    #[repr(align(16384))]
+    struct Heap([u8; HEAP_SIZE]);
+    static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+
+    assert!(mem::size_of::<FreeBlock>() <= MIN_BLOCK_SIZE);
+    let allocator = Locked::new(BuddyAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.0.as_mut_ptr() as usize, HEAP_SIZE)
+    };
+    let whole = order_of(&Layout::from_size_align(HEAP_SIZE, 1).unwrap()).unwrap();
+    assert_eq!(allocator.lock().stats().free_blocks[whole], 1);
+
+    let small = Layout::from_size_align(24, 8).unwrap();
+    let page = Layout::from_size_align(4096, 4096).unwrap();
+    unsafe {
+        let a = allocator.alloc(small);
+        let b = allocator.alloc(page);
+        assert_eq!(b as usize % 4096, 0);
+        // the 32-byte block splits every order down from the whole heap
+        assert_eq!(allocator.lock().stats().heap_free, HEAP_SIZE - 32 - 4096);
+        allocator.dealloc(a, small);
+        allocator.dealloc(b, page);
+    }
+    // all blocks are merged again
+    let stats = allocator.lock().stats();
+    assert_eq!(stats.heap_free, HEAP_SIZE);
+    assert_eq!(stats.free_blocks.iter().sum::<usize>(), 1);
+}
+
+#[test_case]
+fn test_buddy_unaligned_heap() {
+    const HEAP_SIZE: usize = 4096;
+    #[repr(align(4096))]
+    struct Heap([u8; HEAP_SIZE]);
+    static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+
+    let allocator = Locked::new(BuddyAllocator::new());
+    // the heap ends 15 bytes before the end of `HEAP`, and must not be rounded up to it
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.0.as_mut_ptr() as usize + 1, HEAP_SIZE - MIN_BLOCK_SIZE)
+    };
+    assert_eq!(
+        allocator.lock().stats().heap_free,
+        HEAP_SIZE - 2 * MIN_BLOCK_SIZE
+    );
+}
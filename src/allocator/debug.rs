@@ -0,0 +1,161 @@
+use crate::backtrace::{symbolize, Backtrace};
+use crate::serial_println;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// The maximum number of allocations tracked at once.
+const MAX_TRACKED: usize = 2048;
+/// The number of frames recorded as the call-site of an allocation.
+const CALL_SITE_FRAMES: usize = 4;
+
+/// Functions inside the allocator, which are skipped to find the call-site.
+const ALLOCATOR_SYMBOLS: &[&str] = &[
+    "haribote::allocator",
+    "<haribote::allocator",
+    "__rust_",
+    "__rg_",
+    "alloc::",
+    "<alloc::",
+    "core::alloc",
+];
+
+/// An allocation made while the debug mode is enabled.
+#[derive(Debug, Clone, Copy)]
+struct LiveAllocation {
+    address: usize,
+    size: usize,
+    /// Return addresses of the code requesting the allocation, from the innermost frame.
+    call_site: [u64; CALL_SITE_FRAMES],
+}
+
+struct Tracker {
+    allocations: [Option<LiveAllocation>; MAX_TRACKED],
+    /// The number of allocations not tracked since `allocations` was full.
+    dropped: usize,
+}
+
+/// Whether new allocations are tracked.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The number of `Some` in the allocations of `TRACKER`, changed while it is locked.
+/// Frees don't lock `TRACKER` when this is 0.
+static TRACKED: AtomicUsize = AtomicUsize::new(0);
+/// Locked by the allocators while they are locked, so never allocate memory holding this.
+/// Memory is freed in interrupt handlers too, so lock this with interrupts disabled.
+static TRACKER: Mutex<Tracker> = Mutex::new(Tracker {
+    allocations: [None; MAX_TRACKED],
+    dropped: 0,
+});
+
+/// Start or stop tracking allocations. Allocations tracked already are kept until they are freed.
+/// The call-sites are symbolized only if the symbol table is patched into the kernel, see
+/// backtrace.rs.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Record the allocation at `address` with its call-site if the debug mode is enabled.
+/// This function is only to be called by the allocators.
+#[inline(always)]
+pub fn record_alloc(address: usize, size: usize) {
+    if !is_enabled() {
+        return;
+    }
+    let backtrace = Backtrace::capture();
+    let mut call_site = [0; CALL_SITE_FRAMES];
+    let frames = backtrace.frames().iter().skip_while(|&&address| {
+        symbolize(address.saturating_sub(1)).map_or(false, |(name, _)| {
+            ALLOCATOR_SYMBOLS
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+        })
+    });
+    for (slot, &address) in call_site.iter_mut().zip(frames) {
+        *slot = address;
+    }
+
+    without_interrupts(|| {
+        let mut tracker = TRACKER.lock();
+        match tracker.allocations.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(LiveAllocation {
+                    address,
+                    size,
+                    call_site,
+                });
+                TRACKED.fetch_add(1, Ordering::Relaxed);
+            }
+            None => tracker.dropped += 1,
+        }
+    })
+}
+
+/// Forget the allocation at `address` if it is tracked.
+/// This function is only to be called by the allocators.
+pub fn record_dealloc(address: usize) {
+    if TRACKED.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    without_interrupts(|| {
+        let mut tracker = TRACKER.lock();
+        let slot = tracker
+            .allocations
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |allocation| allocation.address == address));
+        if let Some(slot) = slot {
+            *slot = None;
+            TRACKED.fetch_sub(1, Ordering::Relaxed);
+        }
+    })
+}
+
+/// The number of tracked allocations not freed yet.
+pub fn live_allocations() -> usize {
+    TRACKED.load(Ordering::Relaxed)
+}
+
+/// Print the tracked allocations not freed yet with their call-sites to the serial port.
+/// Interrupts are disabled while printing, since the tracker is too large to be copied.
+pub fn dump() {
+    without_interrupts(dump_locked)
+}
+
+fn dump_locked() {
+    let tracker = TRACKER.lock();
+    serial_println!(
+        "{} live allocations ({} not tracked)",
+        TRACKED.load(Ordering::Relaxed),
+        tracker.dropped
+    );
+    for allocation in tracker.allocations.iter().flatten() {
+        serial_println!("{:#x} {} bytes", allocation.address, allocation.size);
+        for &address in allocation
+            .call_site
+            .iter()
+            .take_while(|&&address| address != 0)
+        {
+            match symbolize(address.saturating_sub(1)) {
+                Some((name, offset)) => {
+                    serial_println!("    at {:#x} {}+{:#x}", address, name, offset + 1)
+                }
+                None => serial_println!("    at {:#x} ??", address),
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_tracking() {
+    use alloc::boxed::Box;
+    let before = live_allocations();
+    set_enabled(true);
+    let value = Box::new(42);
+    set_enabled(false);
+    assert_eq!(live_allocations(), before + 1);
+    drop(value);
+    assert_eq!(live_allocations(), before);
+}
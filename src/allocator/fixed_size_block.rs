@@ -1,16 +1,139 @@
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr;
+use core::{fmt, ptr};
 struct ListNode {
     next: Option<&'static mut ListNode>,
 }
-use super::Locked;
+use super::{debug, ClassStats, GrowFn, Locked};
 
 /// The block size to use.
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
 
+/// Blocks smaller than this are carved from slabs. Larger ones are allocated from the fallback
+/// allocator one by one.
+const SLAB_BLOCK_LIMIT: usize = 4096;
+
+// Blocks of each size class are carved from slabs, which are regions allocated from the fallback
+// allocator and aligned to their size. A slab begins with `Slab` and the rest is divided into
+// blocks of one size class, so the slab of a block is found by masking its address.
+// When all blocks of a slab are freed, the slab is returned to the fallback allocator, and the
+// memory can be used by other size classes. One empty slab is kept for each size class not to
+// return and carve a slab repeatedly, and these slabs are returned when the fallback runs short.
+
+/// Header of a slab.
+struct Slab {
+    /// The next slab in the list of slabs with free blocks.
+    next: *mut Slab,
+    /// Free blocks in this slab.
+    free: Option<&'static mut ListNode>,
+    /// The number of allocated blocks in this slab.
+    used: usize,
+}
+
+/// Size of the slabs of the size class, which holds at least 7 blocks.
+fn slab_size(index: usize) -> usize {
+    (BLOCK_SIZES[index] * 8).max(4096)
+}
+
+/// Offset of the first block in a slab, keeping blocks aligned to their size.
+fn first_block_offset(index: usize) -> usize {
+    super::align_up(mem::size_of::<Slab>(), BLOCK_SIZES[index])
+}
+
+fn blocks_per_slab(index: usize) -> usize {
+    (slab_size(index) - first_block_offset(index)) / BLOCK_SIZES[index]
+}
+
+fn slab_layout(index: usize) -> Layout {
+    Layout::from_size_align(slab_size(index), slab_size(index)).unwrap()
+}
+
 pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    /// Slabs which have free blocks, for each size class.
+    partial_slabs: [*mut Slab; BLOCK_SIZES.len()],
+    /// An empty slab kept for each size class.
+    empty_slabs: [*mut Slab; BLOCK_SIZES.len()],
+    /// The number of slabs of each size class.
+    slabs: [usize; BLOCK_SIZES.len()],
+    /// The number of free blocks in the slabs of each size class.
+    free_blocks: [usize; BLOCK_SIZES.len()],
+    /// Counters of each size class.
+    class_stats: [ClassStats; BLOCK_SIZES.len()],
+    /// Counters of allocations larger than any size class, which go to the fallback directly.
+    large_stats: ClassStats,
     fallback_allocator: linked_list_allocator::Heap,
+    /// Extends the fallback heap when it is exhausted. None for allocators which don't grow.
+    grow: Option<GrowFn>,
+}
+
+// The slabs are owned by the allocator and only accessed through it.
+unsafe impl Send for FixedSizeBlockAllocator {}
+
+/// Snapshot of the statistics of `FixedSizeBlockAllocator`.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// Counters of each size class in `BLOCK_SIZES`.
+    pub classes: [ClassStats; BLOCK_SIZES.len()],
+    /// The number of slabs of each size class.
+    pub slabs: [usize; BLOCK_SIZES.len()],
+    /// Free blocks in the slabs of each size class.
+    pub free_blocks: [usize; BLOCK_SIZES.len()],
+    /// Counters of allocations larger than any size class.
+    pub large: ClassStats,
+    /// Size of the fallback heap, and the bytes used and free in it.
+    pub heap_size: usize,
+    pub heap_used: usize,
+    pub heap_free: usize,
+}
+
+impl Stats {
+    /// Fragmentation in percent, i.e. how much of the memory used in the fallback heap is free
+    /// blocks of the size classes, which only their size class can use.
+    pub fn fragmentation(&self) -> usize {
+        let free_bytes: usize = (0..BLOCK_SIZES.len())
+            .map(|index| self.free_blocks[index] * BLOCK_SIZES[index])
+            .sum();
+        if self.heap_used == 0 {
+            0
+        } else {
+            free_bytes * 100 / self.heap_used
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>6} {:>10} {:>10} {:>8} {:>10} {:>6} {:>8}",
+            "size", "allocs", "frees", "live", "bytes", "slabs", "cached"
+        )?;
+        for (i, class) in self.classes.iter().enumerate() {
+            writeln!(
+                f,
+                "{:>6} {:>10} {:>10} {:>8} {:>10} {:>6} {:>8}",
+                BLOCK_SIZES[i],
+                class.allocs,
+                class.frees,
+                class.live,
+                class.bytes,
+                self.slabs[i],
+                self.free_blocks[i]
+            )?;
+        }
+        writeln!(
+            f,
+            "{:>6} {:>10} {:>10} {:>8} {:>10}",
+            "large", self.large.allocs, self.large.frees, self.large.live, self.large.bytes
+        )?;
+        write!(
+            f,
+            "heap: {} of {} bytes used, {} free, {}% fragmented",
+            self.heap_used,
+            self.heap_size,
+            self.heap_free,
+            self.fragmentation()
+        )
+    }
 }
 
 fn list_index(layout: &Layout) -> Option<usize> {
@@ -21,54 +144,208 @@ fn list_index(layout: &Layout) -> Option<usize> {
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
         Self {
-            list_heads: [None; BLOCK_SIZES.len()],
+            partial_slabs: [ptr::null_mut(); BLOCK_SIZES.len()],
+            empty_slabs: [ptr::null_mut(); BLOCK_SIZES.len()],
+            slabs: [0; BLOCK_SIZES.len()],
+            free_blocks: [0; BLOCK_SIZES.len()],
+            class_stats: [ClassStats::new(); BLOCK_SIZES.len()],
+            large_stats: ClassStats::new(),
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            grow: None,
         }
     }
     pub unsafe fn init(&mut self, heap_start: usize, heap_end: usize) {
         self.fallback_allocator.init(heap_start, heap_end);
     }
+    /// Let the heap grow with `grow` when it is exhausted. The heap doesn't grow without this.
+    pub fn set_grow(&mut self, grow: GrowFn) {
+        self.grow = Some(grow);
+    }
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+        // return the empty slabs and try again
+        if self.reclaim() > 0 {
+            if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+        }
+        // grow the heap and try again
+        let grown = self
+            .grow
+            .map_or(0, |grow| grow(self.fallback_allocator.top(), &layout));
+        if grown == 0 {
+            return ptr::null_mut();
+        }
+        unsafe {
+            self.fallback_allocator.extend(grown);
+        }
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
         }
     }
+    /// Allocate a slab of the size class from the fallback allocator and divide it into blocks.
+    unsafe fn new_slab(&mut self, index: usize) -> *mut Slab {
+        let slab_ptr = self.fallback_alloc(slab_layout(index)) as *mut Slab;
+        if slab_ptr.is_null() {
+            return slab_ptr;
+        }
+        let block_size = BLOCK_SIZES[index];
+        assert!(mem::size_of::<ListNode>() <= block_size);
+        assert!(mem::align_of::<ListNode>() <= block_size);
+        let mut free = None;
+        // link the blocks from the end, so that blocks are allocated from the start
+        for i in (0..blocks_per_slab(index)).rev() {
+            let node_ptr =
+                (slab_ptr as usize + first_block_offset(index) + block_size * i) as *mut ListNode;
+            node_ptr.write(ListNode { next: free });
+            free = Some(&mut *node_ptr);
+        }
+        slab_ptr.write(Slab {
+            next: ptr::null_mut(),
+            free,
+            used: 0,
+        });
+        self.slabs[index] += 1;
+        self.free_blocks[index] += blocks_per_slab(index);
+        slab_ptr
+    }
+    /// Return the empty slab to the fallback allocator.
+    unsafe fn release_slab(&mut self, index: usize, slab_ptr: *mut Slab) {
+        self.slabs[index] -= 1;
+        self.free_blocks[index] -= blocks_per_slab(index);
+        let ptr = NonNull::new(slab_ptr as *mut u8).unwrap();
+        self.fallback_allocator.deallocate(ptr, slab_layout(index));
+    }
+    /// Return the empty slabs kept for each size class to the fallback allocator.
+    /// Returns the number of bytes returned.
+    pub fn reclaim(&mut self) -> usize {
+        let mut reclaimed = 0;
+        for index in 0..BLOCK_SIZES.len() {
+            let slab_ptr = mem::replace(&mut self.empty_slabs[index], ptr::null_mut());
+            if !slab_ptr.is_null() {
+                unsafe { self.release_slab(index, slab_ptr) };
+                reclaimed += slab_size(index);
+            }
+        }
+        reclaimed
+    }
+    unsafe fn slab_alloc(&mut self, index: usize) -> *mut u8 {
+        if self.partial_slabs[index].is_null() {
+            let slab_ptr = match mem::replace(&mut self.empty_slabs[index], ptr::null_mut()) {
+                slab_ptr if !slab_ptr.is_null() => slab_ptr,
+                _ => self.new_slab(index),
+            };
+            if slab_ptr.is_null() {
+                return ptr::null_mut();
+            }
+            self.partial_slabs[index] = slab_ptr;
+        }
+        let slab = &mut *self.partial_slabs[index];
+        let node = slab.free.take().unwrap();
+        slab.free = node.next.take();
+        slab.used += 1;
+        self.free_blocks[index] -= 1;
+        if slab.free.is_none() {
+            // the slab is full
+            self.partial_slabs[index] = mem::replace(&mut slab.next, ptr::null_mut());
+        }
+        node as *mut ListNode as *mut u8
+    }
+    unsafe fn slab_dealloc(&mut self, index: usize, ptr: *mut u8) {
+        let slab_ptr = (ptr as usize & !(slab_size(index) - 1)) as *mut Slab;
+        let slab = &mut *slab_ptr;
+        let was_full = slab.free.is_none();
+        let node_ptr = ptr as *mut ListNode;
+        node_ptr.write(ListNode {
+            next: slab.free.take(),
+        });
+        slab.free = Some(&mut *node_ptr);
+        slab.used -= 1;
+        self.free_blocks[index] += 1;
+
+        if slab.used == 0 {
+            if !was_full {
+                self.unlink_partial(index, slab_ptr);
+            }
+            if self.empty_slabs[index].is_null() {
+                self.empty_slabs[index] = slab_ptr;
+            } else {
+                self.release_slab(index, slab_ptr);
+            }
+        } else if was_full {
+            slab.next = self.partial_slabs[index];
+            self.partial_slabs[index] = slab_ptr;
+        }
+    }
+    /// Remove the slab from the list of slabs with free blocks.
+    unsafe fn unlink_partial(&mut self, index: usize, slab_ptr: *mut Slab) {
+        let mut link: *mut *mut Slab = &mut self.partial_slabs[index];
+        while !(*link).is_null() {
+            if *link == slab_ptr {
+                *link = (*slab_ptr).next;
+                (*slab_ptr).next = ptr::null_mut();
+                return;
+            }
+            link = &mut (**link).next;
+        }
+    }
+    fn stats_of(&mut self, layout: &Layout) -> &mut ClassStats {
+        match list_index(layout) {
+            Some(index) => &mut self.class_stats[index],
+            None => &mut self.large_stats,
+        }
+    }
+    /// Counters of allocations and the usage of the fallback heap.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            classes: self.class_stats,
+            slabs: self.slabs,
+            free_blocks: self.free_blocks,
+            large: self.large_stats,
+            heap_size: self.fallback_allocator.size(),
+            heap_used: self.fallback_allocator.used(),
+            heap_free: self.fallback_allocator.free(),
+        }
+    }
 }
 
 use core::{mem, ptr::NonNull};
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => match allocator.list_heads[index].take() {
-                Some(node) => {
-                    allocator.list_heads[index] = node.next.take();
-                    node as *mut ListNode as *mut u8
-                }
-                None => {
-                    let block_size = BLOCK_SIZES[index];
-                    let block_align = block_size;
-                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                    allocator.fallback_alloc(layout)
-                }
-            },
+        let ptr = match list_index(&layout) {
+            Some(index) if BLOCK_SIZES[index] < SLAB_BLOCK_LIMIT => allocator.slab_alloc(index),
+            Some(index) => {
+                let block_size = BLOCK_SIZES[index];
+                let block_align = block_size;
+                let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                allocator.fallback_alloc(layout)
+            }
             None => allocator.fallback_alloc(layout),
+        };
+        if !ptr.is_null() {
+            allocator.stats_of(&layout).record_alloc(layout.size());
+            debug::record_alloc(ptr as usize, layout.size());
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        allocator.stats_of(&layout).record_free(layout.size());
+        debug::record_dealloc(ptr as usize);
         match list_index(&layout) {
+            Some(index) if BLOCK_SIZES[index] < SLAB_BLOCK_LIMIT => {
+                allocator.slab_dealloc(index, ptr)
+            }
             Some(index) => {
-                let current_head = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let node_ptr = ptr as *mut ListNode;
-                node_ptr.write(current_head);
-                allocator.list_heads[index] = Some(&mut *node_ptr);
+                let block_size = BLOCK_SIZES[index];
+                let layout = Layout::from_size_align(block_size, block_size).unwrap();
+                let ptr = NonNull::new(ptr).unwrap();
+                allocator.fallback_allocator.deallocate(ptr, layout)
             }
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
@@ -77,3 +354,82 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         }
     }
 }
+
+#[test_case]
+fn test_stats() {
+    const HEAP_SIZE: usize = 4096 * 4;
+    #[repr(align(4096))]
+    struct Heap([u8; HEAP_SIZE]);
+    static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.0.as_mut_ptr() as usize, HEAP_SIZE)
+    };
+    let small = Layout::from_size_align(12, 4).unwrap();
+    let large = Layout::from_size_align(5000, 8).unwrap();
+    unsafe {
+        let a = allocator.alloc(small);
+        let b = allocator.alloc(small);
+        let c = allocator.alloc(large);
+        allocator.dealloc(a, small);
+
+        let stats = allocator.lock().stats();
+        let class = stats.classes[list_index(&small).unwrap()];
+        assert_eq!(class.allocs, 2);
+        assert_eq!(class.frees, 1);
+        assert_eq!(class.live, 1);
+        assert_eq!(class.bytes, 12);
+        let index = list_index(&small).unwrap();
+        assert_eq!(stats.slabs[index], 1);
+        assert_eq!(stats.free_blocks[index], blocks_per_slab(index) - 1);
+        assert_eq!(stats.large.live, 1);
+        assert_eq!(stats.large.bytes, 5000);
+        assert_eq!(stats.heap_size, HEAP_SIZE);
+        assert_eq!(stats.heap_used + stats.heap_free, HEAP_SIZE);
+        assert!(stats.fragmentation() > 0);
+
+        allocator.dealloc(b, small);
+        allocator.dealloc(c, large);
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.large.live, 0);
+        assert_eq!(stats.classes[list_index(&small).unwrap()].live, 0);
+    }
+}
+
+#[test_case]
+fn test_reclaim() {
+    const HEAP_SIZE: usize = 4096 * 16;
+    #[repr(align(4096))]
+    struct Heap([u8; HEAP_SIZE]);
+    static mut HEAP: Heap = Heap([0; HEAP_SIZE]);
+
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(HEAP.0.as_mut_ptr() as usize, HEAP_SIZE)
+    };
+    // fill most of the heap with one size class, free them, and then do the same with another
+    // size class, which needs the memory returned by the first one
+    const MIN_SIZE: usize = 16;
+    let mut ptrs = [ptr::null_mut(); HEAP_SIZE * 3 / 4 / MIN_SIZE];
+    for &size in &[MIN_SIZE, 512, MIN_SIZE] {
+        let layout = Layout::from_size_align(size, size).unwrap();
+        let count = HEAP_SIZE * 3 / 4 / size;
+        for ptr in &mut ptrs[..count] {
+            *ptr = unsafe { allocator.alloc(layout) };
+            assert!(!ptr.is_null());
+        }
+        for &ptr in &ptrs[..count] {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        // only one empty slab is kept
+        let stats = allocator.lock().stats();
+        assert_eq!(stats.slabs[list_index(&layout).unwrap()], 1);
+    }
+    assert_eq!(allocator.lock().reclaim(), 4096 * 2);
+    assert_eq!(allocator.lock().stats().heap_used, 0);
+}
@@ -0,0 +1,239 @@
+// When the CPU has a local APIC, `init` disables the legacy PICs and routes the keyboard, the RTC
+// and the mouse through the I/O APIC, and the local APIC timer generates the timer interrupt
+// instead of PIT. The vectors are the same as the ones of `interrupts::InterruptIndex`, so the
+// handlers are shared with the PICs.
+
+use crate::acpi::Madt;
+use crate::interrupts::InterruptIndex;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::PortWriteOnly;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Virtual address where the registers of the local APIC are mapped.
+const LAPIC_VIRT: u64 = 0x_5555_5555_0000;
+/// Virtual address where the registers of the I/O APIC are mapped.
+const IOAPIC_VIRT: u64 = 0x_5555_5555_1000;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+/// Global enable flag of IA32_APIC_BASE.
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// registers of the local APIC
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+/// Software enable flag of the spurious interrupt vector register.
+const SVR_ENABLE: u32 = 1 << 8;
+/// Mask flag of LVT entries and I/O APIC redirection entries.
+const MASKED: u32 = 1 << 16;
+/// Periodic mode flag of the LVT timer entry.
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// Active low flag of I/O APIC redirection entries.
+const ACTIVE_LOW: u32 = 1 << 13;
+/// Level-triggered flag of I/O APIC redirection entries.
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+/// Divide the bus clock by 16 for the local APIC timer.
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+/// The local APIC timer is counted for this duration to calibrate it against PIT.
+const CALIBRATION_US: u64 = 10_000;
+
+// ISA IRQs. The I/O APIC inputs they are connected to are found in the MADT, e.g. PIT is
+// connected to the input 2 instead of 0 on almost all PCs including QEMU.
+const IRQ_PIT: u8 = 0;
+const IRQ_KEYBOARD: u8 = 1;
+const IRQ_RTC: u8 = 8;
+const IRQ_MOUSE: u8 = 12;
+
+/// Set once the APICs are initialized. End of interrupts are notified to the local APIC after that.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The number of local APIC timer counts in one tick.
+static TIMER_COUNT_PER_TICK: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the CPU has a local APIC.
+pub fn is_supported() -> bool {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    cpuid.edx & (1 << 9) != 0
+}
+
+/// Whether the interrupts are delivered through the APICs instead of the PICs.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The number of local APIC timer counts in one tick, measured by `init`.
+pub fn timer_count_per_tick() -> u64 {
+    TIMER_COUNT_PER_TICK.load(Ordering::Relaxed)
+}
+
+/// Disable the PICs, route the keyboard, the RTC and the mouse through the I/O APIC described by
+/// `madt` and start the local APIC timer at `timer::frequency()`.
+///
+/// This function is unsafe because the caller must guarantee that the APIC is supported
+/// (`is_supported`), the PICs are already remapped and interrupts are disabled.
+pub unsafe fn init(
+    madt: &Madt,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    let lapic_phys = apic_base.read() & 0x000f_ffff_ffff_f000;
+    map_registers(LAPIC_VIRT, lapic_phys, mapper, frame_allocator)?;
+    map_registers(
+        IOAPIC_VIRT,
+        madt.ioapic_address.as_u64(),
+        mapper,
+        frame_allocator,
+    )?;
+
+    disable_pics();
+
+    apic_base.write(apic_base.read() | APIC_BASE_ENABLE);
+    write_lapic(LAPIC_TPR, 0);
+    write_lapic(
+        LAPIC_SVR,
+        SVR_ENABLE | InterruptIndex::ApicSpurious.as_u8() as u32,
+    );
+
+    let lapic_id = (read_lapic(LAPIC_ID) >> 24) as u8;
+    // PIT is left masked, since the local APIC timer generates the timer interrupt
+    set_redirection(madt, IRQ_PIT, InterruptIndex::Timer, lapic_id, true);
+    set_redirection(
+        madt,
+        IRQ_KEYBOARD,
+        InterruptIndex::Keyboard,
+        lapic_id,
+        false,
+    );
+    set_redirection(madt, IRQ_RTC, InterruptIndex::Rtc, lapic_id, false);
+    set_redirection(madt, IRQ_MOUSE, InterruptIndex::Mouse, lapic_id, false);
+
+    start_timer();
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Notify the end of interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    unsafe { write_lapic(LAPIC_EOI, 0) };
+}
+
+/// Map the 4KiB registers at `phys` to `virt` without caching.
+fn map_registers(
+    virt: u64,
+    phys: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page = Page::containing_address(VirtAddr::new(virt));
+    let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    unsafe {
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+    }
+    Ok(())
+}
+
+/// Mask all the interrupts of the PICs.
+unsafe fn disable_pics() {
+    PortWriteOnly::<u8>::new(0xa1).write(0xff);
+    PortWriteOnly::<u8>::new(0x21).write(0xff);
+}
+
+/// Measure the local APIC timer against PIT, then let it raise the timer interrupt every tick.
+unsafe fn start_timer() {
+    use crate::timer;
+
+    write_lapic(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write_lapic(LAPIC_LVT_TIMER, MASKED);
+    write_lapic(LAPIC_TIMER_INITIAL_COUNT, core::u32::MAX);
+    timer::delay_us(CALIBRATION_US);
+    let elapsed = (core::u32::MAX - read_lapic(LAPIC_TIMER_CURRENT_COUNT)) as u64;
+
+    let count_per_tick = elapsed * 1_000_000 / CALIBRATION_US / timer::frequency() as u64;
+    let count_per_tick = crate::util::clip(count_per_tick, 1, core::u32::MAX as u64);
+    TIMER_COUNT_PER_TICK.store(count_per_tick, Ordering::Relaxed);
+
+    write_lapic(
+        LAPIC_LVT_TIMER,
+        TIMER_PERIODIC | InterruptIndex::Timer.as_u8() as u32,
+    );
+    write_lapic(LAPIC_TIMER_INITIAL_COUNT, count_per_tick as u32);
+}
+
+/// Route ISA IRQ `irq` to `vector` of the local APIC `lapic_id`. The input of the I/O APIC, the
+/// polarity and the trigger mode follow the interrupt source override of `madt` if any.
+unsafe fn set_redirection(
+    madt: &Madt,
+    irq: u8,
+    vector: InterruptIndex,
+    lapic_id: u8,
+    masked: bool,
+) {
+    let isa_irq = madt.isa_irq(irq);
+    let input = isa_irq.gsi - madt.ioapic_gsi_base;
+    let mut low = vector.as_u8() as u32;
+    if masked {
+        low |= MASKED;
+    }
+    if isa_irq.active_low {
+        low |= ACTIVE_LOW;
+    }
+    if isa_irq.level_triggered {
+        low |= LEVEL_TRIGGERED;
+    }
+    let high = (lapic_id as u32) << 24;
+    write_ioapic(0x10 + input * 2 + 1, high);
+    write_ioapic(0x10 + input * 2, low);
+}
+
+unsafe fn read_lapic(register: usize) -> u32 {
+    core::ptr::read_volatile((LAPIC_VIRT as usize + register) as *const u32)
+}
+
+unsafe fn write_lapic(register: usize, value: u32) {
+    core::ptr::write_volatile((LAPIC_VIRT as usize + register) as *mut u32, value);
+}
+
+/// Write to the I/O APIC register selected through IOREGSEL (offset 0x00) and IOWIN (offset 0x10).
+unsafe fn write_ioapic(register: u32, value: u32) {
+    core::ptr::write_volatile(IOAPIC_VIRT as *mut u32, register);
+    core::ptr::write_volatile((IOAPIC_VIRT + 0x10) as *mut u32, value);
+}
+
+#[test_case]
+fn test_apic_enabled() {
+    // QEMU emulates APICs
+    assert!(is_enabled());
+    assert!(timer_count_per_tick() > 0);
+}
+
+#[test_case]
+fn test_lapic_timer_ticks() {
+    use crate::clocksource;
+    use crate::timer::{delay_us, frequency, TIMER_CONTROL};
+    let count =
+        || x86_64::instructions::interrupts::without_interrupts(|| TIMER_CONTROL.lock().count);
+    let start = (count(), clocksource::now());
+    delay_us(200_000);
+    let ticks = count().wrapping_sub(start.0);
+    let elapsed = clocksource::now() - start.1;
+    // the ticks expected at the calibrated frequency in the elapsed time. The calibration is
+    // inaccurate under emulation, so 25% of error is allowed, and one tick for the phase.
+    let expected = elapsed * frequency() as u64 / 1_000_000_000;
+    assert!(
+        ticks * 4 + 4 >= expected * 3 && ticks * 4 <= expected * 5 + 4,
+        "{} ticks in {}ns",
+        ticks,
+        elapsed
+    );
+}
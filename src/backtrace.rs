@@ -0,0 +1,197 @@
+use core::fmt;
+use x86_64::VirtAddr;
+
+// Frame pointers are forced by `-C force-frame-pointers=yes` in .cargo/config.toml, so every
+// function begins with `push rbp; mov rbp, rsp`. Therefore `[rbp]` is the rbp of the caller and
+// `[rbp + 8]` is the return address into the caller.
+//
+// The symbol table is a placeholder without symbols generated by build.rs. After linking,
+// symbols/process_symbols.py writes the table generated from the kernel ELF into the
+// `.kernel_symbols` section of the ELF itself, so no function moves and the addresses stay correct.
+// `cargo run` and `cargo test` run the script as the runner before booting the kernel.
+
+/// Size of the symbol table. Must be the same as SYMBOL_TABLE_SIZE of build.rs and
+/// process_symbols.py.
+const SYMBOL_TABLE_SIZE: usize = 0x80000;
+#[link_section = ".kernel_symbols"]
+#[used]
+static SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] =
+    *include_bytes!(concat!(env!("OUT_DIR"), "/symbols.bin"));
+
+/// The maximum number of frames in a backtrace.
+const MAX_FRAMES: usize = 32;
+
+/// Return addresses of the callers, from the innermost frame.
+/// This doesn't allocate memory, so it can be used while the heap is broken.
+#[derive(Clone)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Walk the stack of the caller of this function.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp) };
+        Self::walk(None, rbp)
+    }
+    /// Walk the stack of the code interrupted at `rip` with the frame pointer `rbp`, e.g. the one
+    /// in `ExceptionFrame`. `rip` itself is the first frame.
+    pub fn from_frame(rip: u64, rbp: u64) -> Self {
+        Self::walk(Some(rip), rbp)
+    }
+    fn walk(rip: Option<u64>, mut rbp: u64) -> Self {
+        let mut backtrace = Self {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        };
+        if let Some(rip) = rip {
+            backtrace.push(rip);
+        }
+        // the chain ends with 0, which is the rbp of newly created tasks
+        while rbp != 0 && rbp % 8 == 0 && backtrace.len < MAX_FRAMES {
+            // the chain may be corrupt, e.g. when an exception is reported, and reading an
+            // unmapped frame would fault again in the exception handler
+            if !is_readable(rbp) || !is_readable(rbp + 8) {
+                break;
+            }
+            let (caller_rbp, return_address) =
+                unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+            if return_address == 0 {
+                break;
+            }
+            backtrace.push(return_address);
+            // the stack grows downwards, so frames of callers are at higher addresses
+            if caller_rbp <= rbp {
+                break;
+            }
+            rbp = caller_rbp;
+        }
+        backtrace
+    }
+    fn push(&mut self, address: u64) {
+        self.frames[self.len] = address;
+        self.len += 1;
+    }
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+/// Whether `address` can be read without faulting.
+fn is_readable(address: u64) -> bool {
+    VirtAddr::try_new(address).map_or(false, crate::memory::is_mapped)
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "backtrace:")?;
+        for (i, &address) in self.frames().iter().enumerate() {
+            write!(f, "#{:<2} {:#x}", i, address)?;
+            // a return address points to the next of the call, which may be another function
+            match symbolize(address.saturating_sub(1)) {
+                Some((name, offset)) => writeln!(f, " {}+{:#x}", name, offset + 1)?,
+                None => writeln!(f, " ??")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The symbol table in memory. The compiler knows only the placeholder, so the table is accessed
+/// through a pointer obtained by a volatile read, which the compiler can't see through and
+/// therefore never folds reads of the placeholder into constants.
+fn symbol_table() -> &'static [u8] {
+    let table: *const [u8; SYMBOL_TABLE_SIZE] = &SYMBOL_TABLE;
+    unsafe { &*core::ptr::read_volatile(&table) }
+}
+
+/// The name of the function containing `address` and the offset from its start.
+/// Returns None if the symbol table is not patched into the kernel.
+pub fn symbolize(address: u64) -> Option<(&'static str, u64)> {
+    lookup(symbol_table(), address)
+}
+
+/// Find the symbol containing `address` in `table` generated by process_symbols.py.
+fn lookup(table: &[u8], address: u64) -> Option<(&str, u64)> {
+    let u32_at = |offset: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(table.get(offset..offset + 4)?);
+        Some(u32::from_le_bytes(bytes))
+    };
+    let u64_at = |offset: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(table.get(offset..offset + 8)?);
+        Some(u64::from_le_bytes(bytes))
+    };
+    const HEADER_SIZE: usize = 8;
+    const ENTRY_SIZE: usize = 16;
+
+    if table.get(..4)? != b"KSYM" {
+        return None;
+    }
+    let count = u32_at(4)? as usize;
+    // entries are sorted by address; find the last one not after `address`
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if u64_at(HEADER_SIZE + ENTRY_SIZE * mid)? <= address {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let entry = HEADER_SIZE + ENTRY_SIZE * low.checked_sub(1)?;
+    let symbol_address = u64_at(entry)?;
+    let name_offset = u32_at(entry + 8)? as usize;
+    let name_len = u32_at(entry + 12)? as usize;
+    let name = core::str::from_utf8(table.get(name_offset..name_offset + name_len)?).ok()?;
+    Some((name, address - symbol_address))
+}
+
+#[test_case]
+fn test_lookup() {
+    use alloc::vec::Vec;
+    let mut table = Vec::new();
+    table.extend_from_slice(b"KSYM");
+    table.extend_from_slice(&2u32.to_le_bytes());
+    let names_start = 8 + 16 * 2;
+    for &(address, offset, len) in &[(0x1000u64, 0, 3), (0x2000u64, 3, 4)] {
+        table.extend_from_slice(&address.to_le_bytes());
+        table.extend_from_slice(&(names_start + offset as u32).to_le_bytes());
+        table.extend_from_slice(&(len as u32).to_le_bytes());
+    }
+    table.extend_from_slice(b"foobar_");
+
+    assert_eq!(lookup(&table, 0x0fff), None);
+    assert_eq!(lookup(&table, 0x1000), Some(("foo", 0)));
+    assert_eq!(lookup(&table, 0x1fff), Some(("foo", 0xfff)));
+    assert_eq!(lookup(&table, 0x2010), Some(("bar_", 0x10)));
+    assert_eq!(lookup(&[0; 16], 0x1000), None);
+}
+
+#[test_case]
+fn test_capture() {
+    #[inline(never)]
+    fn callee() -> Backtrace {
+        Backtrace::capture()
+    }
+    let backtrace = callee();
+    // at least the return address into this test
+    assert!(!backtrace.frames().is_empty());
+}
+
+#[test_case]
+fn test_corrupt_frames() {
+    // unmapped and non-canonical frame pointers end the walk without faulting
+    for &rbp in &[0xdead_beaf_000, 0x8000_0000_0000_0000, u64::MAX - 7] {
+        assert_eq!(Backtrace::from_frame(0x1234, rbp).frames(), [0x1234]);
+    }
+    // a frame pointing to a lower address ends the walk
+    let mut frame = [0u64, 0x5678];
+    frame[0] = frame.as_ptr() as u64 - 16;
+    let backtrace = Backtrace::from_frame(0x1234, frame.as_ptr() as u64);
+    assert_eq!(backtrace.frames(), [0x1234, 0x5678]);
+}
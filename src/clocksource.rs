@@ -0,0 +1,253 @@
+use crate::asm;
+use crate::timer::{self, TIMER_CONTROL};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::registers::rflags;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Virtual address where the registers of HPET are mapped.
+const HPET_VIRT: u64 = 0x_5555_5555_2000;
+/// TSC is counted for this duration to calibrate it against PIT.
+const CALIBRATION_US: u64 = 10_000;
+
+/// A clock counting nanoseconds from an arbitrary point, e.g. the boot.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+    /// Current time in nanoseconds. This never goes backwards.
+    fn now(&self) -> u64;
+}
+
+/// The clock of the timer interrupt. Its resolution is one tick, or one PIT clock while the timer
+/// interrupt comes from PIT.
+pub struct Pit {
+    /// The last time returned, which keeps `now` monotonic around the reload of the counter.
+    last: AtomicU64,
+}
+
+/// The time stamp counter of the CPU, calibrated against PIT by `init`.
+pub struct Tsc {
+    hz: u64,
+}
+
+/// The main counter of HPET, found through the ACPI HPET table.
+pub struct Hpet {
+    /// Period of the main counter in femtoseconds.
+    period_fs: u64,
+}
+
+static PIT: Pit = Pit {
+    last: AtomicU64::new(0),
+};
+static TSC: Once<Tsc> = Once::new();
+static HPET: Once<Hpet> = Once::new();
+/// The clock source used by `now`.
+static CURRENT: Once<&'static dyn ClockSource> = Once::new();
+
+impl ClockSource for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+    fn now(&self) -> u64 {
+        let rf = rflags::read();
+        asm::cli();
+        let count = TIMER_CONTROL.lock().count;
+        let counter = timer::read_pit_counter();
+        rflags::write(rf);
+
+        let mut nanos = timer::ticks_to_duration(count).as_nanos() as u64;
+        // the counter is in phase with ticks only while PIT raises the timer interrupt
+        if !crate::apic::is_enabled() {
+            let elapsed = timer::pit_divisor() - counter;
+            nanos += elapsed * 1_000_000_000 / timer::PIT_CLOCK_HZ as u64;
+        }
+        core::cmp::max(self.last.fetch_max(nanos, Ordering::Relaxed), nanos)
+    }
+}
+
+impl Tsc {
+    /// Count TSC while waiting with PIT. Returns None if the CPU has no TSC.
+    fn calibrate() -> Option<Self> {
+        let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+        if cpuid.edx & (1 << 4) == 0 {
+            return None;
+        }
+        let start = unsafe { core::arch::x86_64::_rdtsc() };
+        timer::delay_us(CALIBRATION_US);
+        let elapsed = unsafe { core::arch::x86_64::_rdtsc() } - start;
+        Some(Self {
+            hz: elapsed * 1_000_000 / CALIBRATION_US,
+        })
+    }
+    /// Frequency of TSC in Hz.
+    pub fn frequency(&self) -> u64 {
+        self.hz
+    }
+    /// Whether TSC runs at a constant rate regardless of the power state of the CPU.
+    pub fn is_invariant(&self) -> bool {
+        let cpuid = unsafe { core::arch::x86_64::__cpuid(0x8000_0007) };
+        cpuid.edx & (1 << 8) != 0
+    }
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+    fn now(&self) -> u64 {
+        let tsc = unsafe { core::arch::x86_64::_rdtsc() };
+        (tsc as u128 * 1_000_000_000 / self.hz as u128) as u64
+    }
+}
+
+// registers of HPET
+const HPET_CAPABILITIES: u64 = 0x00;
+const HPET_CONFIGURATION: u64 = 0x10;
+const HPET_MAIN_COUNTER: u64 = 0xf0;
+/// COUNT_SIZE_CAP of the capabilities register, set if the main counter is 64 bit.
+const HPET_COUNTER_64BIT: u64 = 1 << 13;
+/// ENABLE_CNF of the general configuration register.
+const HPET_ENABLE: u64 = 1;
+
+impl Hpet {
+    /// Map the registers of HPET at `base` and start the main counter.
+    /// Returns None if the main counter is 32 bit. It wraps around in about 5 minutes at 14.3MHz,
+    /// and `now` would go backwards.
+    unsafe fn init(
+        base: PhysAddr,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<Option<Self>, MapToError<Size4KiB>> {
+        let page = Page::containing_address(VirtAddr::new(HPET_VIRT));
+        let frame = PhysFrame::containing_address(base);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+
+        let capabilities = read_hpet(HPET_CAPABILITIES);
+        if capabilities & HPET_COUNTER_64BIT == 0 {
+            return Ok(None);
+        }
+        let period_fs = capabilities >> 32;
+        write_hpet(
+            HPET_CONFIGURATION,
+            read_hpet(HPET_CONFIGURATION) | HPET_ENABLE,
+        );
+        Ok(Some(Self { period_fs }))
+    }
+    /// Frequency of the main counter in Hz.
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+    fn now(&self) -> u64 {
+        let counter = unsafe { read_hpet(HPET_MAIN_COUNTER) };
+        (counter as u128 * self.period_fs as u128 / 1_000_000) as u64
+    }
+}
+
+unsafe fn read_hpet(register: u64) -> u64 {
+    core::ptr::read_volatile((HPET_VIRT + register) as *const u64)
+}
+
+unsafe fn write_hpet(register: u64, value: u64) {
+    core::ptr::write_volatile((HPET_VIRT + register) as *mut u64, value);
+}
+
+/// Calibrate TSC, start HPET if ACPI reports it and its main counter is 64 bit, and select the
+/// clock source used by `now`: invariant TSC, HPET, TSC and PIT in the order of preference.
+///
+/// This function is unsafe because the caller must guarantee that the complete physical memory is
+/// mapped to virtual memory at `physical_memory_offset`.
+pub unsafe fn init(
+    physical_memory_offset: VirtAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    if let Some(tsc) = Tsc::calibrate() {
+        TSC.call_once(|| tsc);
+    }
+    if let Some(table) = crate::acpi::find_table(physical_memory_offset, b"HPET") {
+        // the address of the generic address structure of the base address
+        let base = crate::acpi::read::<u64>(physical_memory_offset, table.as_u64() + 44);
+        if let Some(hpet) = Hpet::init(PhysAddr::new(base), mapper, frame_allocator)? {
+            HPET.call_once(|| hpet);
+        }
+    }
+
+    let current: &'static dyn ClockSource = match (tsc(), hpet()) {
+        (Some(tsc), _) if tsc.is_invariant() => tsc,
+        (_, Some(hpet)) => hpet,
+        (Some(tsc), None) => tsc,
+        (None, None) => &PIT,
+    };
+    CURRENT.call_once(|| current);
+    Ok(())
+}
+
+pub fn pit() -> &'static Pit {
+    &PIT
+}
+
+pub fn tsc() -> Option<&'static Tsc> {
+    TSC.r#try()
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.r#try()
+}
+
+/// The clock source used by `now`. PIT until `init` is called.
+pub fn current() -> &'static dyn ClockSource {
+    match CURRENT.r#try() {
+        Some(&clock) => clock,
+        None => &PIT,
+    }
+}
+
+/// Current time in nanoseconds, read from the best clock source available.
+pub fn now() -> u64 {
+    current().now()
+}
+
+#[test_case]
+fn test_clock_sources() {
+    let mut clocks: alloc::vec::Vec<&dyn ClockSource> = alloc::vec![pit()];
+    clocks.extend(tsc().map(|tsc| tsc as &dyn ClockSource));
+    clocks.extend(hpet().map(|hpet| hpet as &dyn ClockSource));
+    for clock in clocks {
+        let start = clock.now();
+        timer::delay_us(20_000);
+        let elapsed = clock.now() - start;
+        // PIT has the resolution of a tick (10ms) while the local APIC timer is used
+        assert!(
+            10_000_000 <= elapsed && elapsed <= 40_000_000,
+            "{}: {}ns",
+            clock.name(),
+            elapsed
+        );
+    }
+}
+
+#[test_case]
+fn test_hpet_64bit() {
+    // QEMU emulates HPET with a 64 bit main counter, and 32 bit ones are never used
+    assert!(hpet().is_some());
+    assert!(unsafe { read_hpet(HPET_CAPABILITIES) } & HPET_COUNTER_64BIT != 0);
+}
+
+#[test_case]
+fn test_clock_monotonic() {
+    let mut last = now();
+    for _ in 0..1000 {
+        let time = now();
+        assert!(time >= last);
+        last = time;
+    }
+}
@@ -1,60 +1,256 @@
-use alloc::{vec, vec::Vec};
+use crate::asm;
+use crate::rtc::DateTime;
+use crate::timer::TimerId;
+use alloc::{boxed::Box, vec, vec::Vec};
+use pc_keyboard::DecodedKey;
+use spsc::{Consumer, Producer, SpscRing};
+use x86_64::registers::rflags;
 
 /// uses static-sized vector as a buffer
 #[derive(Debug, Clone)]
 pub struct FIFO<T> {
-    buf: Vec<T>,
+    buf: Vec<Option<T>>,
     p: usize,
     q: usize,
     size: usize,
     free: usize,
+    /// The task woken up when data is pushed.
+    task: Option<usize>,
+    policy: OverflowPolicy,
+    /// Set when data is dropped under `OverflowPolicy::Overrun`.
+    overrun: bool,
+    stats: FifoStats,
 }
 
 pub const BUF_SIZE: usize = 2048;
+/// The number of bytes of mouse packets `MOUSE_PACKETS` holds.
+const MOUSE_BUF_SIZE: usize = 256;
 
-pub const KEYBOARD_OFFSET: u32 = 256;
-pub const MOUSE_OFFSET: u32 = 512;
+/// What `FIFO::push` does when the buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the pushed data.
+    DropNewest,
+    /// Discard the oldest data in the buffer to make room for the pushed data.
+    DropOldest,
+    /// Discard the pushed data and set the overrun flag, like FLAGS_OVERRUN of Haribote.
+    Overrun,
+}
+
+/// Statistics of a FIFO buffer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FifoStats {
+    /// The number of calls of `push`, including the ones whose data was dropped.
+    pub pushes: u64,
+    /// The number of data dropped because the buffer was full.
+    pub drops: u64,
+    /// The maximum number of data stored in the buffer at once.
+    pub high_water_mark: usize,
+}
+
+/// Events delivered through FIFO buffers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A timer timed out. Holds the id and the data of the timer.
+    Timer(TimerId, u32),
+    /// A key was pressed.
+    Key(DecodedKey),
+    /// A byte of a mouse packet, forwarded from `MOUSE_PACKETS` by `route_mouse_packets`.
+    MousePacket(u8),
+    /// The RTC updated the time, once a second.
+    Rtc(DateTime),
+}
 
 use lazy_static::lazy_static;
 use spin::Mutex;
 lazy_static! {
     /// Unified FIFO buffer of haribote OS.
-    /// 0: ticking x
-    /// 1: ticking y
-    /// 3: 3 sec have passed
-    /// 10: 10 sec have passed
-    /// 256-511: keyboard input (offset 256)
-    /// 512-767: mouse input (offset 512)
-    pub static ref GLOBAL_FIFO_BUF: Mutex<FIFO<u32>> = Mutex::new(FIFO::new(BUF_SIZE, 0));
+    /// The RTC and the timers of `kernel_loop` push their events here, and so do the keyboard and
+    /// the mouse unless `EVENT_ROUTER` routes them to another FIFO.
+    pub static ref GLOBAL_FIFO_BUF: Mutex<FIFO<Event>> = Mutex::new(FIFO::new(BUF_SIZE));
+    /// Decides which FIFO receives the events from the keyboard and the mouse.
+    pub static ref EVENT_ROUTER: Mutex<EventRouter> = Mutex::new(EventRouter::new(&GLOBAL_FIFO_BUF));
+    /// Bytes of mouse packets. Initialize this after the heap, since the first mouse interrupt
+    /// must not allocate memory.
+    pub static ref MOUSE_PACKETS: MousePackets = {
+        let ring = SpscRing::new(MOUSE_BUF_SIZE, crate::task::wake);
+        let (producer, consumer) = Box::leak(Box::new(ring)).split();
+        MousePackets {
+            producer: Mutex::new(producer),
+            consumer: Mutex::new(consumer),
+        }
+    };
+}
+
+/// Bytes of mouse packets, pushed by the mouse interrupt handler and popped by the task forwarding
+/// them with `route_mouse_packets`, which registers itself with `Consumer::set_task` to be woken
+/// up.
+/// Unlike FIFOs, neither of them needs `cli`, since the producer is locked only by the interrupt
+/// handler and the consumer only by the task.
+pub struct MousePackets {
+    pub producer: Mutex<Producer<'static, u8>>,
+    pub consumer: Mutex<Consumer<'static, u8>>,
+}
+
+/// Routes device events to the FIFO of their consumer.
+/// Keyboard events go to the FIFO of the focused window, or to `default` if no window is focused.
+/// Mouse packets go to `mouse` through `route_mouse_packets`.
+/// Like other FIFOs, this is locked in interrupt handlers, so lock it after `cli`.
+pub struct EventRouter {
+    /// FIFO receiving keyboard events when no window is focused.
+    default: &'static Mutex<FIFO<Event>>,
+    /// FIFO receiving mouse packets.
+    mouse: &'static Mutex<FIFO<Event>>,
+    /// Pairs of sheet id and FIFO of the windows which accept keyboard input, in the order of
+    /// registration.
+    windows: Vec<(usize, &'static Mutex<FIFO<Event>>)>,
+    /// The sheet id of the focused window.
+    focus: Option<usize>,
+}
+
+impl EventRouter {
+    pub fn new(default: &'static Mutex<FIFO<Event>>) -> Self {
+        Self {
+            default,
+            mouse: default,
+            windows: vec![],
+            focus: None,
+        }
+    }
+    /// Let the window of `sheet_id` receive keyboard events through `fifo` while it is focused.
+    pub fn register(&mut self, sheet_id: usize, fifo: &'static Mutex<FIFO<Event>>) {
+        self.unregister(sheet_id);
+        self.windows.push((sheet_id, fifo));
+    }
+    /// Stop delivering events to the window of `sheet_id`. It loses the focus if it had.
+    pub fn unregister(&mut self, sheet_id: usize) {
+        self.windows.retain(|&(id, _)| id != sheet_id);
+        if self.focus == Some(sheet_id) {
+            self.focus = None;
+        }
+    }
+    /// Focus the window of `sheet_id`, or no window if None or the window is not registered.
+    pub fn set_focus(&mut self, sheet_id: Option<usize>) {
+        self.focus =
+            sheet_id.filter(|&sheet_id| self.windows.iter().any(|&(id, _)| id == sheet_id));
+    }
+    /// Move the focus to the window registered next to the focused one.
+    pub fn focus_next(&mut self) {
+        if self.windows.is_empty() {
+            self.focus = None;
+            return;
+        }
+        let next = match self.focus {
+            Some(focus) => match self.windows.iter().position(|&(id, _)| id == focus) {
+                Some(i) => (i + 1) % self.windows.len(),
+                None => 0,
+            },
+            None => 0,
+        };
+        self.focus = Some(self.windows[next].0);
+    }
+    /// The sheet id of the focused window.
+    pub fn focus(&self) -> Option<usize> {
+        self.focus
+    }
+    /// FIFO to which keyboard events are pushed now.
+    pub fn keyboard(&self) -> &'static Mutex<FIFO<Event>> {
+        self.focus
+            .and_then(|focus| self.windows.iter().find(|&&(id, _)| id == focus))
+            .map_or(self.default, |&(_, fifo)| fifo)
+    }
+    /// FIFO to which mouse packets are pushed.
+    pub fn mouse(&self) -> &'static Mutex<FIFO<Event>> {
+        self.mouse
+    }
+    /// Change the FIFO receiving mouse packets.
+    pub fn set_mouse(&mut self, fifo: &'static Mutex<FIFO<Event>>) {
+        self.mouse = fifo;
+    }
 }
 
-impl<T: Clone> FIFO<T> {
-    pub fn new(buf_size: usize, default_value: T) -> Self {
+/// Forward the mouse packets in `MOUSE_PACKETS` to the FIFO chosen by `EVENT_ROUTER`, which wakes
+/// up its task. Call this from the task set to the consumer of `MOUSE_PACKETS`.
+/// Returns false if some packets were dropped since the FIFO was full.
+pub fn route_mouse_packets() -> bool {
+    let mut routed = true;
+    loop {
+        let packet = match MOUSE_PACKETS.consumer.lock().pop() {
+            Some(packet) => packet,
+            None => return routed,
+        };
+        let rf = rflags::read();
+        asm::cli();
+        let fifo = EVENT_ROUTER.lock().mouse();
+        routed &= fifo.lock().push(Event::MousePacket(packet)).is_ok();
+        rflags::write(rf);
+    }
+}
+
+impl<T> FIFO<T> {
+    /// Create a FIFO holding up to `buf_size` data. Panics if `buf_size` is 0, since such a FIFO
+    /// could hold nothing, and `OverflowPolicy::DropOldest` would have nothing to drop.
+    pub fn new(buf_size: usize) -> Self {
+        assert!(buf_size > 0, "FIFO must have nonzero capacity");
+        let mut buf = Vec::with_capacity(buf_size);
+        buf.resize_with(buf_size, || None);
         Self {
-            buf: vec![default_value; buf_size],
+            buf,
             p: 0,
             q: 0,
             free: buf_size,
             size: buf_size,
+            task: None,
+            policy: OverflowPolicy::Overrun,
+            overrun: false,
+            stats: FifoStats::default(),
         }
     }
+    /// Set what to do when data is pushed to the full buffer. Defaults to `OverflowPolicy::Overrun`.
+    pub fn set_policy(&mut self, policy: OverflowPolicy) {
+        self.policy = policy;
+    }
+    /// Set the task which consumes this FIFO. The task is woken up whenever data is pushed.
+    pub fn set_task(&mut self, task: Option<usize>) {
+        self.task = task;
+    }
+    /// Push data to the buffer.
+    /// Returns `Err` if the buffer was full and some data, either the pushed one or the oldest one
+    /// according to the policy, was dropped.
     pub fn push(&mut self, data: T) -> Result<(), ()> {
+        self.stats.pushes += 1;
+        let mut result = Ok(());
         if self.free == 0 {
-            return Err(());
+            self.stats.drops += 1;
+            result = Err(());
+            match self.policy {
+                OverflowPolicy::DropNewest => return result,
+                OverflowPolicy::Overrun => {
+                    self.overrun = true;
+                    return result;
+                }
+                OverflowPolicy::DropOldest => {
+                    self.pop().unwrap();
+                }
+            }
         }
-        self.buf[self.p] = data;
+        self.buf[self.p] = Some(data);
         self.p += 1;
         if self.p == self.size {
             self.p = 0;
         }
         self.free -= 1;
-        Ok(())
+        self.stats.high_water_mark = core::cmp::max(self.stats.high_water_mark, self.status());
+        if let Some(task) = self.task {
+            crate::task::wake(task);
+        }
+        result
     }
     pub fn pop(&mut self) -> Result<T, ()> {
         if self.free == self.size {
             return Err(());
         }
-        let data = self.buf[self.q].clone();
+        let data = self.buf[self.q].take().unwrap();
         self.q += 1;
         if self.q == self.size {
             self.q = 0;
@@ -65,4 +261,160 @@ impl<T: Clone> FIFO<T> {
     pub fn status(&self) -> usize {
         return self.size - self.free;
     }
+    /// Whether data was dropped under `OverflowPolicy::Overrun` since the last `clear_overrun`.
+    pub fn overrun(&self) -> bool {
+        self.overrun
+    }
+    pub fn clear_overrun(&mut self) {
+        self.overrun = false;
+    }
+    pub fn stats(&self) -> FifoStats {
+        self.stats
+    }
+    pub fn reset_stats(&mut self) {
+        self.stats = FifoStats::default();
+    }
+}
+
+#[test_case]
+fn test_event_router_focus() {
+    use alloc::boxed::Box;
+    let default: &'static Mutex<FIFO<Event>> = Box::leak(Box::new(Mutex::new(FIFO::new(4))));
+    let window: &'static Mutex<FIFO<Event>> = Box::leak(Box::new(Mutex::new(FIFO::new(4))));
+    let mut router = EventRouter::new(default);
+    assert!(core::ptr::eq(router.keyboard(), default));
+
+    router.register(1, window);
+    router.set_focus(Some(1));
+    assert!(core::ptr::eq(router.keyboard(), window));
+    // unregistered windows can't be focused
+    router.set_focus(Some(2));
+    assert_eq!(router.focus(), None);
+
+    router.register(2, default);
+    router.focus_next();
+    assert_eq!(router.focus(), Some(1));
+    router.focus_next();
+    assert_eq!(router.focus(), Some(2));
+    router.unregister(2);
+    assert_eq!(router.focus(), None);
+    assert!(core::ptr::eq(router.keyboard(), default));
+}
+
+#[test_case]
+fn test_overflow_policy() {
+    let mut fifo = FIFO::new(2);
+    fifo.set_policy(OverflowPolicy::DropNewest);
+    assert_eq!(fifo.push(1), Ok(()));
+    assert_eq!(fifo.push(2), Ok(()));
+    assert_eq!(fifo.push(3), Err(()));
+    assert_eq!(fifo.pop(), Ok(1));
+    assert_eq!(fifo.pop(), Ok(2));
+    assert!(!fifo.overrun());
+
+    fifo.set_policy(OverflowPolicy::DropOldest);
+    fifo.push(1).unwrap();
+    fifo.push(2).unwrap();
+    assert_eq!(fifo.push(3), Err(()));
+    assert_eq!(fifo.pop(), Ok(2));
+    assert_eq!(fifo.pop(), Ok(3));
+    assert!(!fifo.overrun());
+
+    fifo.set_policy(OverflowPolicy::Overrun);
+    fifo.push(1).unwrap();
+    fifo.push(2).unwrap();
+    assert_eq!(fifo.push(3), Err(()));
+    assert!(fifo.overrun());
+    assert_eq!(fifo.pop(), Ok(1));
+    fifo.clear_overrun();
+    assert!(!fifo.overrun());
+}
+
+#[test_case]
+fn test_fifo_stats() {
+    let mut fifo = FIFO::new(3);
+    fifo.push(1).unwrap();
+    fifo.push(2).unwrap();
+    fifo.pop().unwrap();
+    fifo.push(3).unwrap();
+    fifo.push(4).unwrap();
+    fifo.push(5).unwrap_err();
+    assert_eq!(
+        fifo.stats(),
+        FifoStats {
+            pushes: 5,
+            drops: 1,
+            high_water_mark: 3,
+        }
+    );
+    fifo.reset_stats();
+    assert_eq!(fifo.stats(), FifoStats::default());
+}
+
+#[test_case]
+fn test_spsc_interrupt_producer() {
+    use crate::interrupts::TIMER_TEST_HOOK;
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    const COUNT: u32 = 400;
+    // the timer interrupt pushes more than the ring holds, so that it finds the ring full and
+    // retries the rest in the next tick
+    const PUSHES_PER_TICK: u32 = 8;
+    static PRODUCER: Mutex<Option<(Producer<'static, u32>, u32)>> = Mutex::new(None);
+    fn push_from_interrupt() {
+        if let Some((producer, next)) = PRODUCER.lock().as_mut() {
+            for _ in 0..PUSHES_PER_TICK {
+                if *next == COUNT || producer.push(*next).is_err() {
+                    break;
+                }
+                *next += 1;
+            }
+        }
+    }
+
+    let (producer, mut consumer) = Box::leak(Box::new(SpscRing::new(4, crate::task::wake))).split();
+    without_interrupts(|| {
+        *PRODUCER.lock() = Some((producer, 0));
+        *TIMER_TEST_HOOK.lock() = Some(push_from_interrupt);
+    });
+    // the data arrive in order while the timer interrupt is pushing, and nothing is lost
+    let mut expected = 0;
+    while expected < COUNT {
+        match consumer.pop() {
+            Some(data) => {
+                assert_eq!(data, expected);
+                expected += 1;
+            }
+            None => x86_64::instructions::hlt(),
+        }
+    }
+    without_interrupts(|| {
+        *TIMER_TEST_HOOK.lock() = None;
+        *PRODUCER.lock() = None;
+    });
+    assert_eq!(consumer.pop(), None);
+}
+
+#[test_case]
+fn test_route_mouse_packets() {
+    let fifo: &'static Mutex<FIFO<Event>> = Box::leak(Box::new(Mutex::new(FIFO::new(2))));
+    let rf = rflags::read();
+    asm::cli();
+    // the mouse interrupt handler isn't running with interrupts disabled
+    for packet in 1..=3 {
+        MOUSE_PACKETS.producer.lock().push(packet).unwrap();
+    }
+    let previous = EVENT_ROUTER.lock().mouse();
+    EVENT_ROUTER.lock().set_mouse(fifo);
+    rflags::write(rf);
+
+    // the packet which doesn't fit in the FIFO is dropped
+    assert!(!route_mouse_packets());
+    assert_eq!(MOUSE_PACKETS.consumer.lock().status(), 0);
+    asm::cli();
+    EVENT_ROUTER.lock().set_mouse(previous);
+    assert_eq!(fifo.lock().pop(), Ok(Event::MousePacket(1)));
+    assert_eq!(fifo.lock().pop(), Ok(Event::MousePacket(2)));
+    assert_eq!(fifo.lock().pop(), Err(()));
+    rflags::write(rf);
 }
@@ -1,19 +1,26 @@
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::paging::{mapper::UnmapError, Mapper, Page, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_DEFAULT_IST_INDEX: u16 = 0;
 
+/// Size of the stack for the double fault handler, including the guard page at the bottom.
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+/// Page-aligned so that the lowest page can be unmapped as the guard page by `init_guard_page`.
+#[repr(align(4096))]
+struct Stack([u8; DOUBLE_FAULT_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: Stack = Stack([0; DOUBLE_FAULT_STACK_SIZE]);
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_DEFAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [usize; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+            let stack_start = VirtAddr::from_ptr(unsafe { &DOUBLE_FAULT_STACK });
+            let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
             stack_end
         };
         tss
@@ -51,3 +58,16 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Unmap the lowest page of the stack for the double fault handler, so that its overflow faults
+/// instead of overwriting the memory below.
+/// This must be called after `memory::init_global`.
+pub fn init_guard_page() -> Result<(), UnmapError> {
+    let page =
+        Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(unsafe { &DOUBLE_FAULT_STACK }));
+    crate::memory::with_mapper(|mapper, _| {
+        let (_frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        Ok(())
+    })
+}
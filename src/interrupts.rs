@@ -1,30 +1,34 @@
-use crate::gdt;
-use crate::println;
+use crate::sync::IrqMutex;
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
 use ps2_mouse::{Mouse, MouseState};
 use spin::Mutex;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
+/// handlers of CPU exceptions, which dump registers
+pub mod exception;
+
 lazy_static! {
     /// register handler functions to IDT
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        // breakpoint
-        idt.breakpoint.set_handler_fn(handler::breakpoint_handler);
-        // double fault
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(handler::double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_DEFAULT_IST_INDEX);
-        }
-        idt.page_fault.set_handler_fn(handler::page_fault_handler);
+        // CPU exceptions
+        exception::set_handlers(&mut idt);
         // timer
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(handler::timer_interrupt_handler);
         // keyboard
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(handler::keyboard_interrupt_handler);
         // mouse
         idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(handler::mouse_interrupt_handler);
+        // RTC
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(handler::rtc_interrupt_handler);
+        // spurious interrupts of the local APIC and the PICs
+        idt[InterruptIndex::ApicSpurious.as_usize()]
+            .set_handler_fn(handler::apic_spurious_interrupt_handler);
+        idt[InterruptIndex::Pic1Spurious.as_usize()]
+            .set_handler_fn(handler::pic1_spurious_interrupt_handler);
+        idt[InterruptIndex::Pic2Spurious.as_usize()]
+            .set_handler_fn(handler::pic2_spurious_interrupt_handler);
         idt
     };
 }
@@ -32,20 +36,6 @@ lazy_static! {
 /// handler functions
 mod handler {
     use super::*;
-    pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-        println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-    }
-
-    pub extern "x86-interrupt" fn double_fault_handler(
-        stack_frame: &mut InterruptStackFrame,
-        error_code: u64,
-    ) -> ! {
-        panic!(
-            "EXCEPTION: DOUBLE FAULT\n{:#?}, {:#?}",
-            stack_frame, error_code
-        );
-    }
-
     pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
         use crate::asm;
         use crate::timer::TIMER_CONTROL;
@@ -53,24 +43,37 @@ mod handler {
 
         let rf = rflags::read();
         asm::cli();
-        {
+        let (tasking, task_switch) = {
             let mut tc_locked = TIMER_CONTROL.lock();
-            tc_locked.count += 1;
-            tc_locked.shift_timers();
+            tc_locked.count = tc_locked.count.wrapping_add(1);
+            (tc_locked.task_timer.is_some(), tc_locked.shift_timers())
+        };
+        // tasks are accounted only after `task::init` is called
+        if tasking {
+            crate::task::account_tick();
+        }
+        #[cfg(test)]
+        {
+            if let Some(hook) = *super::TIMER_TEST_HOOK.lock() {
+                hook();
+            }
         }
-        rflags::write(rf);
 
         // notify end of interrupt
-        unsafe {
-            PICS.lock()
-                .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        notify_end_of_interrupt(InterruptIndex::Timer);
+
+        // switch task after EOI, or the timer interrupt would never come again until this task is
+        // switched back
+        if task_switch {
+            crate::task::switch();
         }
+        rflags::write(rf);
     }
 
     pub extern "x86-interrupt" fn keyboard_interrupt_handler(
         _stack_frame: &mut InterruptStackFrame,
     ) {
-        use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+        use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
         use x86_64::instructions::port::Port;
 
         lazy_static! {
@@ -86,25 +89,15 @@ mod handler {
 
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
             if let Some(key) = keyboard.process_keyevent(key_event) {
-                use crate::fifo;
-                fifo::GLOBAL_FIFO_BUF
-                    .lock()
-                    .push(
-                        match key {
-                            DecodedKey::Unicode(character) => character,
-                            DecodedKey::RawKey(_) => '?',
-                        } as u32
-                            + fifo::KEYBOARD_OFFSET,
-                    )
-                    .unwrap();
+                use crate::fifo::{Event, EVENT_ROUTER};
+                let fifo = EVENT_ROUTER.lock().keyboard();
+                // overflow is recorded in the statistics of the FIFO
+                let _ = fifo.lock().push(Event::Key(key));
             }
         }
 
         // notify end of interrupt
-        unsafe {
-            PICS.lock()
-                .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8())
-        }
+        notify_end_of_interrupt(InterruptIndex::Keyboard);
     }
 
     pub extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
@@ -112,39 +105,62 @@ mod handler {
 
         let mut port: PortReadOnly<u8> = PortReadOnly::new(0x60);
         let packet = unsafe { port.read() };
-        // we assume this is single-threaded as static variables are used here
-        use crate::fifo;
-        fifo::GLOBAL_FIFO_BUF
-            .lock()
-            .push(packet as u32 + fifo::MOUSE_OFFSET)
-            .unwrap();
+        // the producer is locked only here, so this never spins
+        // the packet is dropped if the ring is full
+        let _ = crate::fifo::MOUSE_PACKETS.producer.lock().push(packet);
 
         // notify end of interrupt
-        unsafe {
-            PICS.lock()
-                .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8())
-        }
+        notify_end_of_interrupt(InterruptIndex::Mouse);
     }
 
-    use x86_64::structures::idt::PageFaultErrorCode;
-    pub extern "x86-interrupt" fn page_fault_handler(
-        stack_frame: &mut InterruptStackFrame,
-        error_code: PageFaultErrorCode,
+    pub extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+        use crate::fifo::{Event, GLOBAL_FIFO_BUF};
+
+        crate::rtc::acknowledge_interrupt();
+        // the time doesn't change for about a second after the update-ended interrupt
+        let time = crate::rtc::read();
+        // overflow is recorded in the statistics of the FIFO
+        let _ = GLOBAL_FIFO_BUF.lock().push(Event::Rtc(time));
+
+        // notify end of interrupt
+        notify_end_of_interrupt(InterruptIndex::Rtc);
+    }
+
+    /// The local APIC doesn't need the end of interrupt for spurious interrupts.
+    pub extern "x86-interrupt" fn apic_spurious_interrupt_handler(
+        _stack_frame: &mut InterruptStackFrame,
     ) {
-        use crate::hlt_loop;
-        use x86_64::registers::control::Cr2;
-        println!("EXCEPTION: PAGE FAULT");
-        println!("Accessed address: {:?}", Cr2::read());
-        println!("Error code: {:?}", error_code);
-        println!("{:#?}", stack_frame);
-        hlt_loop();
+    }
+
+    /// The PICs raise the IRQ 7 as a spurious interrupt even if they are masked, and the master
+    /// PIC doesn't need the end of interrupt for it. No device is connected to the IRQ 7.
+    pub extern "x86-interrupt" fn pic1_spurious_interrupt_handler(
+        _stack_frame: &mut InterruptStackFrame,
+    ) {
+    }
+
+    /// Likewise the IRQ 15 of the slave PIC. The master PIC doesn't know it is spurious, so it
+    /// needs the end of interrupt while the PICs are used.
+    pub extern "x86-interrupt" fn pic2_spurious_interrupt_handler(
+        _stack_frame: &mut InterruptStackFrame,
+    ) {
+        if !crate::apic::is_enabled() {
+            use x86_64::instructions::port::PortWriteOnly;
+            unsafe { PortWriteOnly::<u8>::new(0x20).write(0x20) };
+        }
     }
 } /* handler */
 
 lazy_static! {
-    pub static ref MOUSE: Mutex<Mouse> = Mutex::new(Mouse::new());
+    /// Locked by tasks processing mouse packets, so this is an `IrqMutex`.
+    pub static ref MOUSE: IrqMutex<Mouse> = IrqMutex::new(Mouse::new());
 }
 
+/// Called on every timer interrupt, so that tests can run code in an interrupt handler.
+/// Lock this with interrupts disabled.
+#[cfg(test)]
+pub static TIMER_TEST_HOOK: Mutex<Option<fn()>> = Mutex::new(None);
+
 pub fn init_idt() {
     {
         let mut locked_mouse = MOUSE.lock();
@@ -196,11 +212,15 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 0x08;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard = PIC_1_OFFSET + 0x01,
+    Rtc = PIC_2_OFFSET,
     Mouse = PIC_2_OFFSET + 0x04,
+    Pic1Spurious = PIC_1_OFFSET + 0x07,
+    Pic2Spurious = PIC_2_OFFSET + 0x07,
+    ApicSpurious = 0xff,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -212,6 +232,15 @@ impl InterruptIndex {
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Notify the end of interrupt to the local APIC, or to the PICs if the APICs are not used.
+fn notify_end_of_interrupt(index: InterruptIndex) {
+    if crate::apic::is_enabled() {
+        crate::apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) }
+    }
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
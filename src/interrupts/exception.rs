@@ -0,0 +1,426 @@
+use crate::serial_println;
+use core::fmt;
+use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable, PageFaultErrorCode};
+
+// Exception entries.
+// The CPU pushes SS, RSP, RFLAGS, CS, RIP and, for some exceptions, an error code. The stubs of the
+// other exceptions push 0 instead so that all exceptions have the same frame. Then the vector and
+// the general purpose registers are pushed and `exception_handler` is called with the pointer to
+// them as `ExceptionFrame`. The CPU aligns the stack to 16 bytes before pushing its frame, and the
+// stub pushes 22 registers in total, so the stack is still aligned at the call.
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.macro EXCEPTION_STUB vector, error_code
+.global exception_stub_\vector
+exception_stub_\vector:
+.if \error_code == 0
+    push 0
+.endif
+    push \vector
+    jmp exception_common
+.endm
+
+EXCEPTION_STUB 0, 0
+EXCEPTION_STUB 1, 0
+EXCEPTION_STUB 2, 0
+EXCEPTION_STUB 3, 0
+EXCEPTION_STUB 4, 0
+EXCEPTION_STUB 5, 0
+EXCEPTION_STUB 6, 0
+EXCEPTION_STUB 7, 0
+EXCEPTION_STUB 8, 1
+EXCEPTION_STUB 9, 0
+EXCEPTION_STUB 10, 1
+EXCEPTION_STUB 11, 1
+EXCEPTION_STUB 12, 1
+EXCEPTION_STUB 13, 1
+EXCEPTION_STUB 14, 1
+EXCEPTION_STUB 16, 0
+EXCEPTION_STUB 17, 1
+EXCEPTION_STUB 18, 0
+EXCEPTION_STUB 19, 0
+EXCEPTION_STUB 20, 0
+EXCEPTION_STUB 21, 1
+EXCEPTION_STUB 30, 1
+
+exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call exception_handler
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    iretq
+.att_syntax prefix
+"#
+);
+
+extern "C" {
+    fn exception_stub_0();
+    fn exception_stub_1();
+    fn exception_stub_2();
+    fn exception_stub_3();
+    fn exception_stub_4();
+    fn exception_stub_5();
+    fn exception_stub_6();
+    fn exception_stub_7();
+    fn exception_stub_8();
+    fn exception_stub_9();
+    fn exception_stub_10();
+    fn exception_stub_11();
+    fn exception_stub_12();
+    fn exception_stub_13();
+    fn exception_stub_14();
+    fn exception_stub_16();
+    fn exception_stub_17();
+    fn exception_stub_18();
+    fn exception_stub_19();
+    fn exception_stub_20();
+    fn exception_stub_21();
+    fn exception_stub_30();
+}
+
+/// Reinterpret a stub as the handler function type of an IDT entry.
+/// The stubs return with `iretq` by themselves, so they are valid as any type of handler.
+unsafe fn stub<F>(stub: unsafe extern "C" fn()) -> F {
+    core::mem::transmute_copy(&stub)
+}
+
+/// Register the stubs to all the architectural exceptions.
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    use crate::gdt;
+    unsafe {
+        idt.divide_error.set_handler_fn(stub(exception_stub_0));
+        idt.debug.set_handler_fn(stub(exception_stub_1));
+        idt.non_maskable_interrupt
+            .set_handler_fn(stub(exception_stub_2));
+        idt.breakpoint.set_handler_fn(stub(exception_stub_3));
+        idt.overflow.set_handler_fn(stub(exception_stub_4));
+        idt.bound_range_exceeded
+            .set_handler_fn(stub(exception_stub_5));
+        idt.invalid_opcode.set_handler_fn(stub(exception_stub_6));
+        idt.device_not_available
+            .set_handler_fn(stub(exception_stub_7));
+        idt.double_fault
+            .set_handler_fn(stub(exception_stub_8))
+            .set_stack_index(gdt::DOUBLE_FAULT_DEFAULT_IST_INDEX);
+        idt.coprocessor_segment_overrun
+            .set_handler_fn(stub(exception_stub_9));
+        idt.invalid_tss.set_handler_fn(stub(exception_stub_10));
+        idt.segment_not_present
+            .set_handler_fn(stub(exception_stub_11));
+        idt.stack_segment_fault
+            .set_handler_fn(stub(exception_stub_12));
+        idt.general_protection_fault
+            .set_handler_fn(stub(exception_stub_13));
+        idt.page_fault.set_handler_fn(stub(exception_stub_14));
+        idt.x87_floating_point
+            .set_handler_fn(stub(exception_stub_16));
+        idt.alignment_check.set_handler_fn(stub(exception_stub_17));
+        idt.machine_check.set_handler_fn(stub(exception_stub_18));
+        idt.simd_floating_point
+            .set_handler_fn(stub(exception_stub_19));
+        idt.virtualization.set_handler_fn(stub(exception_stub_20));
+        idt.security_exception
+            .set_handler_fn(stub(exception_stub_30));
+
+        // x86_64 0.12 has no field for #CP, since the vector was reserved. The table consists of
+        // the 256 gates of the IDT in order, so the gate is set directly.
+        let gates = &mut *(idt as *mut InterruptDescriptorTable as *mut [Entry<HandlerFunc>; 256]);
+        gates[21].set_handler_fn(stub(exception_stub_21));
+    }
+}
+
+/// Registers saved by the exception stubs, in the order on the stack.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct ExceptionFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// The error code pushed by the CPU, or 0 for exceptions without it.
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Mnemonic and name of the exception of `vector`.
+pub fn exception_name(vector: u64) -> (&'static str, &'static str) {
+    match vector {
+        0 => ("#DE", "DIVIDE ERROR"),
+        1 => ("#DB", "DEBUG"),
+        2 => ("NMI", "NON-MASKABLE INTERRUPT"),
+        3 => ("#BP", "BREAKPOINT"),
+        4 => ("#OF", "OVERFLOW"),
+        5 => ("#BR", "BOUND RANGE EXCEEDED"),
+        6 => ("#UD", "INVALID OPCODE"),
+        7 => ("#NM", "DEVICE NOT AVAILABLE"),
+        8 => ("#DF", "DOUBLE FAULT"),
+        9 => ("", "COPROCESSOR SEGMENT OVERRUN"),
+        10 => ("#TS", "INVALID TSS"),
+        11 => ("#NP", "SEGMENT NOT PRESENT"),
+        12 => ("#SS", "STACK-SEGMENT FAULT"),
+        13 => ("#GP", "GENERAL PROTECTION FAULT"),
+        14 => ("#PF", "PAGE FAULT"),
+        16 => ("#MF", "X87 FLOATING-POINT EXCEPTION"),
+        17 => ("#AC", "ALIGNMENT CHECK"),
+        18 => ("#MC", "MACHINE CHECK"),
+        19 => ("#XM", "SIMD FLOATING-POINT EXCEPTION"),
+        20 => ("#VE", "VIRTUALIZATION EXCEPTION"),
+        21 => ("#CP", "CONTROL PROTECTION EXCEPTION"),
+        30 => ("#SX", "SECURITY EXCEPTION"),
+        _ => ("", "UNKNOWN EXCEPTION"),
+    }
+}
+
+/// The table referred by `SelectorErrorCode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// Error code of the exceptions caused by a segment selector or an IDT gate:
+/// #TS, #NP, #SS and #GP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode {
+    /// The exception occurred while delivering an external event, e.g. an interrupt.
+    pub external: bool,
+    pub table: DescriptorTable,
+    /// The index of the descriptor in `table`.
+    pub index: u16,
+}
+
+impl SelectorErrorCode {
+    pub fn new(error_code: u64) -> Self {
+        Self {
+            external: error_code & 1 != 0,
+            table: match error_code >> 1 & 0b11 {
+                0b00 => DescriptorTable::Gdt,
+                0b10 => DescriptorTable::Ldt,
+                _ => DescriptorTable::Idt,
+            },
+            index: (error_code >> 3 & 0x1fff) as u16,
+        }
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "index: {}, table: {:?}, external: {}",
+            self.index, self.table, self.external
+        )
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+        let (mnemonic, name) = exception_name(self.vector);
+        writeln!(
+            f,
+            "EXCEPTION: {} ({}, vector {})",
+            name, mnemonic, self.vector
+        )?;
+        write!(f, "error code: {:#x}", self.error_code)?;
+        match self.vector {
+            // 0 means the exception is not related to a selector
+            10 | 11 | 12 | 13 if self.error_code != 0 => {
+                write!(f, " ({})", SelectorErrorCode::new(self.error_code))?
+            }
+            14 => write!(
+                f,
+                " ({:?})\naccessed address: {:?}",
+                PageFaultErrorCode::from_bits_truncate(self.error_code),
+                Cr2::read()
+            )?,
+            _ => {}
+        }
+        writeln!(f)?;
+        if let Some((symbol, offset)) = crate::backtrace::symbolize(self.rip) {
+            writeln!(f, "at {}+{:#x}", symbol, offset)?;
+        }
+
+        let registers = [
+            ("RIP", self.rip),
+            ("RSP", self.rsp),
+            ("RFL", self.rflags),
+            ("CS ", self.cs),
+            ("SS ", self.ss),
+            ("RAX", self.rax),
+            ("RBX", self.rbx),
+            ("RCX", self.rcx),
+            ("RDX", self.rdx),
+            ("RSI", self.rsi),
+            ("RDI", self.rdi),
+            ("RBP", self.rbp),
+            ("R8 ", self.r8),
+            ("R9 ", self.r9),
+            ("R10", self.r10),
+            ("R11", self.r11),
+            ("R12", self.r12),
+            ("R13", self.r13),
+            ("R14", self.r14),
+            ("R15", self.r15),
+            ("CR0", Cr0::read_raw()),
+            ("CR2", Cr2::read().as_u64()),
+            ("CR3", Cr3::read().0.start_address().as_u64()),
+            ("CR4", Cr4::read_raw()),
+        ];
+        // 3 registers per line fit in the 80 columns of the text mode
+        for line in registers.chunks(3) {
+            for (name, value) in line {
+                write!(f, "{}={:016x} ", name, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Called by the exception stubs.
+/// Debug exceptions, NMIs and breakpoints are reported with the backtrace to the serial port and
+/// the interrupted code resumes. They may interrupt code holding any lock, even with interrupts
+/// disabled in the case of NMIs, so the report is dropped if the serial port is locked. Page
+/// faults in lazily-backed regions are resolved by mapping a page, and other page faults and
+/// kernel stack overflows in tasks terminate the task. The other exceptions are fatal and the
+/// kernel panics with the report, which is shown on the screen by the panic handler with the
+/// backtrace.
+#[no_mangle]
+extern "C" fn exception_handler(frame: &mut ExceptionFrame) {
+    use crate::backtrace::Backtrace;
+    use x86_64::registers::control::Cr2;
+    if frame.vector == 14
+        && crate::memory::demand::handle_page_fault(
+            Cr2::read(),
+            PageFaultErrorCode::from_bits_truncate(frame.error_code),
+        )
+    {
+        return;
+    }
+
+    // overflow of a kernel stack faults at its guard page, and the page fault can't be delivered
+    // on the same stack, which results in a double fault
+    if frame.vector == 8 {
+        if let Some((task, entry)) = crate::task::stack_overflow_task(Cr2::read()) {
+            report_stack_overflow(frame, task, entry);
+        }
+    }
+
+    match frame.vector {
+        1 | 2 | 3 => {
+            let backtrace = Backtrace::from_frame(frame.rip, frame.rbp);
+            crate::serial::try_print(format_args!("{}\n{}\n", frame, backtrace));
+        }
+        14 if can_terminate_task(frame) => {
+            let task = crate::task::current();
+            serial_println!("{}", frame);
+            serial_println!("{}", Backtrace::from_frame(frame.rip, frame.rbp));
+            serial_println!("task {} is terminated", task);
+            crate::task::exit();
+        }
+        _ => {
+            serial_println!("{}", frame);
+            panic!("{}", frame)
+        }
+    }
+}
+
+/// Report the overflow of the kernel stack of `task` starting from `entry`, and terminate the task
+/// if possible. Otherwise the kernel panics.
+fn report_stack_overflow(frame: &ExceptionFrame, task: usize, entry: u64) {
+    use crate::backtrace::symbolize;
+    let name = symbolize(entry).map_or("??", |(name, _)| name);
+    if can_terminate_task(frame) {
+        serial_println!("{}", frame);
+        serial_println!("kernel stack overflow in task {} ({})", task, name);
+        serial_println!("task {} is terminated", task);
+        crate::task::exit();
+    }
+    panic!(
+        "kernel stack overflow in task {} ({})\n{}",
+        task, name, frame
+    );
+}
+
+/// Whether the exception happened in a task which can be terminated safely.
+/// The code running with interrupts disabled, e.g. interrupt handlers or code holding locks shared
+/// with them, is regarded as the kernel itself.
+/// Locks shared between tasks are taken with interrupts disabled too, e.g. `IrqMutex` and FIFOs,
+/// so a task faulting with interrupts enabled holds none of them, and terminating it never leaves
+/// them locked. A new lock shared between tasks must follow this rule.
+fn can_terminate_task(frame: &ExceptionFrame) -> bool {
+    use x86_64::registers::rflags::RFlags;
+    frame.rflags & RFlags::INTERRUPT_FLAG.bits() != 0
+        && crate::task::can_exit(crate::task::current())
+}
+
+#[test_case]
+fn test_selector_error_code() {
+    // the IDT gate 3 on delivering an external interrupt
+    assert_eq!(
+        SelectorErrorCode::new(0x1b),
+        SelectorErrorCode {
+            external: true,
+            table: DescriptorTable::Idt,
+            index: 3,
+        }
+    );
+    assert_eq!(
+        SelectorErrorCode::new(0x2c),
+        SelectorErrorCode {
+            external: false,
+            table: DescriptorTable::Ldt,
+            index: 5,
+        }
+    );
+}
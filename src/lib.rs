@@ -3,6 +3,7 @@
 #![feature(abi_x86_interrupt)]
 #![feature(custom_test_frameworks)]
 #![feature(asm)]
+#![feature(global_asm)]
 #![test_runner(test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(alloc_error_handler)]
@@ -20,9 +21,17 @@ extern crate rlibc;
 use bootloader::entry_point;
 use core::panic::PanicInfo;
 
+/// ACPI tables
+pub mod acpi;
 pub mod allocator;
+/// Local APIC and I/O APIC
+pub mod apic;
 /// assembly-specific functions
 pub mod asm;
+/// stack walking and symbolization
+pub mod backtrace;
+/// high-resolution clock sources
+pub mod clocksource;
 /// Unified FIFO buffer
 pub mod fifo;
 /// font files
@@ -33,8 +42,14 @@ pub mod gdt;
 pub mod interrupts;
 /// memory management
 pub mod memory;
+/// CMOS real-time clock
+pub mod rtc;
 /// communicating with serial port
 pub mod serial;
+/// locks shared between tasks
+pub mod sync;
+/// multitasking
+pub mod task;
 /// PIT settings
 pub mod timer;
 /// utility functions
@@ -93,7 +108,7 @@ pub fn init(boot_info: &'static BootInfo) -> x86_64::VirtAddr {
     gdt::init();
 
     // set timer interrupt frequency
-    timer::init_pit();
+    timer::init_pit(timer::DEFAULT_TIMER_HZ);
 
     // initialize IDT
     interrupts::init_idt();
@@ -106,9 +121,38 @@ pub fn init(boot_info: &'static BootInfo) -> x86_64::VirtAddr {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+        unsafe { memory::BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
 
+    // deliver interrupts through APICs instead of the PICs if available. The MADT tells where the
+    // I/O APIC is and how the ISA IRQs are connected to it.
+    if apic::is_supported() {
+        if let Some(madt) = unsafe { acpi::parse_madt(phys_mem_offset) } {
+            unsafe { apic::init(&madt, &mut mapper, &mut frame_allocator) }
+                .expect("APIC initialization failed");
+        }
+    }
+
+    // calibrate TSC and find HPET for high-resolution timekeeping
+    unsafe { clocksource::init(phys_mem_offset, &mut mapper, &mut frame_allocator) }
+        .expect("clock source initialization failed");
+
+    serial_println!(
+        "memory {}MB free : {}KB",
+        frame_allocator.total_memory() / (1024 * 1024),
+        frame_allocator.free_memory() / 1024
+    );
+
+    // the page fault handler maps pages with these
+    memory::init_global(mapper, frame_allocator);
+    gdt::init_guard_page().expect("failed to unmap the guard page of the double fault stack");
+
+    // let the RTC notify the time every second
+    rtc::init();
+
+    // the ring of mouse packets is allocated here, not in the first mouse interrupt
+    lazy_static::initialize(&fifo::MOUSE_PACKETS);
+
     // enable interrupts
     // This should be later than the initialization of memory allocation, since this starts timer
     // interrupt and timer uses FIFO, which internally uses Vec.
@@ -139,6 +183,7 @@ fn panic(info: &PanicInfo) -> ! {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}", backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     loop {}
 }
@@ -177,33 +222,58 @@ pub fn kernel_loop() -> ! {
         (background_id, test_sheet_id)
     };
 
+    let task_a_id = task::init();
+    fifo::MOUSE_PACKETS
+        .consumer
+        .lock()
+        .set_task(Some(task_a_id));
     asm::cli();
-    let timer_ticking_id = {
-        let mut locked_tc = timer::TIMER_CONTROL.lock();
-        // 0.01s x 1000 = 10s
-        let timer_10_sec_id = locked_tc.allocate().unwrap();
-        locked_tc.set_time(timer_10_sec_id, 1000);
-        locked_tc.timers[timer_10_sec_id].data = 10;
-        // 0.01s x 300 = 3s
-        let timer_3_sec_id = locked_tc.allocate().unwrap();
-        locked_tc.set_time(timer_3_sec_id, 300);
-        locked_tc.timers[timer_3_sec_id].data = 3;
-        // 0.01s x 100 = 1s
-        let timer_ticking_id = locked_tc.allocate().unwrap();
-        locked_tc.set_time(timer_ticking_id, 100);
-        locked_tc.timers[timer_ticking_id].data = 1;
-        timer_ticking_id
-    };
+    fifo::GLOBAL_FIFO_BUF.lock().set_task(Some(task_a_id));
+    {
+        // keyboard input goes to test_sheet first
+        let mut event_router = fifo::EVENT_ROUTER.lock();
+        event_router.register(test_sheet_id, &fifo::GLOBAL_FIFO_BUF);
+        event_router.set_focus(Some(test_sheet_id));
+    }
     asm::sti();
+    let task_b_id = task::allocate(task_b_main).unwrap();
+    // task_b counts up in the background, and kernel_loop handling input preempts it
+    task::run(task_b_id, Some(1), 1).unwrap();
+
+    use core::time::Duration;
+    use timer::Timer;
+    let timer_10_sec = Timer::new(&fifo::GLOBAL_FIFO_BUF, 10).unwrap();
+    timer_10_sec.set_oneshot_duration(Duration::from_secs(10));
+    let timer_3_sec = Timer::new(&fifo::GLOBAL_FIFO_BUF, 3).unwrap();
+    timer_3_sec.set_oneshot_duration(Duration::from_secs(3));
+    let timer_ticking = Timer::new(&fifo::GLOBAL_FIFO_BUF, 1).unwrap();
+    timer_ticking.set_periodic_duration(Duration::from_secs(1));
 
     loop {
+        // mouse packets come through the lock-free ring, and are routed to the FIFO of their
+        // consumer, which is GLOBAL_FIFO_BUF unless EVENT_ROUTER is told otherwise
+        fifo::route_mouse_packets();
+
         // FIFOバッファは割り込み時にロックされうるので、
         // 2重ロックを防ぐためにcliしてからロックしないといけない
         asm::cli();
         let fifo_buf_pop_result = fifo::GLOBAL_FIFO_BUF.lock().pop();
+        if fifo_buf_pop_result.is_err() {
+            // sleep until something is pushed to the FIFO or the ring, unless a mouse packet came
+            // after the ring was emptied
+            if fifo::MOUSE_PACKETS.consumer.lock().status() == 0 {
+                task::sleep(task_a_id);
+            }
+            asm::sti();
+            continue;
+        }
         asm::sti();
 
         {
+            asm::cli();
+            let timer_count = timer::TIMER_CONTROL.lock().count;
+            asm::sti();
+
             let mut sheet_control = SHEET_CONTROL.lock();
             let initial_column_position =
                 sheet_control.sheets[test_sheet_id].initial_column_position;
@@ -211,10 +281,6 @@ pub fn kernel_loop() -> ! {
             sheet_control.sheets[test_sheet_id]
                 .boxfill(Color::LightGrey, ((3, 23), (3 + 8 * 15, 23 + 16)));
 
-            asm::cli();
-            let timer_count = timer::TIMER_CONTROL.lock().count;
-            asm::sti();
-
             write!(
                 sheet_control.sheets[test_sheet_id],
                 "Uptime:{:>08}",
@@ -223,52 +289,64 @@ pub fn kernel_loop() -> ! {
             .unwrap();
         }
 
-        if let Ok(data) = fifo_buf_pop_result {
-            use crate::alloc::string::ToString;
-            use core::char::from_u32;
-            match data {
-                256..=511 => write!(
+        if let Ok(event) = fifo_buf_pop_result {
+            use fifo::Event;
+            use pc_keyboard::DecodedKey;
+            match event {
+                Event::Key(DecodedKey::Unicode('\t')) => {
+                    asm::cli();
+                    fifo::EVENT_ROUTER.lock().focus_next();
+                    asm::sti();
+                }
+                Event::Key(key) => write!(
                     SHEET_CONTROL.lock().sheets[test_sheet_id],
                     "{}",
-                    from_u32(data - 256).unwrap().to_string().as_str()
+                    match key {
+                        DecodedKey::Unicode(character) => character,
+                        DecodedKey::RawKey(_) => '?',
+                    }
                 )
                 .unwrap(),
-                512..=767 => crate::interrupts::MOUSE
-                    .lock()
-                    .process_packet((data - 512) as u8),
-                10 => write!(
+                Event::MousePacket(packet) => {
+                    crate::interrupts::MOUSE.lock().process_packet(packet)
+                }
+                Event::Rtc(time) => {
+                    let mut sheet_control = SHEET_CONTROL.lock();
+                    sheet_control.sheets[background_id].draw_clock(time);
+                    sheet_control.flush_printed_chars(Some(0));
+                }
+                Event::Timer(id, _) if id == timer_10_sec.id() => write!(
                     SHEET_CONTROL.lock().sheets[test_sheet_id],
                     "\n\n10 secs have passed",
                 )
                 .unwrap(),
-                3 => {
+                Event::Timer(id, _) if id == timer_3_sec.id() => {
                     write!(
                         SHEET_CONTROL.lock().sheets[test_sheet_id],
                         "\n3 secs have passed",
                     )
                     .unwrap();
                 }
-                1 | 0 => {
-                    asm::cli();
+                Event::Timer(id, data) if id == timer_ticking.id() => {
                     if data == 0 {
-                        timer::TIMER_CONTROL.lock().timers[timer_ticking_id].data = 1;
+                        timer_ticking.set_data(1);
                     } else {
-                        timer::TIMER_CONTROL.lock().timers[timer_ticking_id].data = 0;
+                        timer_ticking.set_data(0);
                     }
-                    timer::TIMER_CONTROL.lock().set_time(timer_ticking_id, 100);
-                    asm::sti();
+                    let cpu_load = task::cpu_load();
                     let mut sheet_control = SHEET_CONTROL.lock();
                     sheet_control.sheets[test_sheet_id].boxfill(
                         Color::LightGrey,
-                        ((3, 23 + 16 * 3), (3 + 8 * 1, 23 + 16 * 4)),
+                        ((3, 23 + 16 * 3), (3 + 8 * 10, 23 + 16 * 4)),
                     );
                     if data == 0 {
                         write!(sheet_control.sheets[test_sheet_id], "\n\n\nx",).unwrap();
                     } else {
                         write!(sheet_control.sheets[test_sheet_id], "\n\n\ny",).unwrap();
                     }
+                    write!(sheet_control.sheets[test_sheet_id], " CPU:{:>3}%", cpu_load).unwrap();
                 }
-                _ => panic!("Unexpected value popped from timer fifo"),
+                Event::Timer(..) => {}
             }
         }
         let mut sheet_control = SHEET_CONTROL.lock();
@@ -276,3 +354,93 @@ pub fn kernel_loop() -> ! {
         sheet_control.flush_printed_chars(Some(test_sheet_height));
     }
 }
+
+/// A task counting up in its own sheet, running concurrently with `kernel_loop`.
+/// It receives its own timer events and keyboard input while its sheet is focused.
+fn task_b_main() -> ! {
+    use alloc::boxed::Box;
+    use core::fmt::Write;
+    use fifo::{Event, FIFO};
+    use pc_keyboard::DecodedKey;
+    use spin::Mutex;
+    use vga_graphic::colors256::Color;
+    use vga_graphic::SHEET_CONTROL;
+
+    let sheet_id = {
+        let mut sheet_control = SHEET_CONTROL.lock();
+        let sheet_id = sheet_control.allocate((150, 68)).unwrap();
+        sheet_control.change_sheet_height(sheet_id, 2);
+        sheet_control.sheets[sheet_id].make_sheet("task_b");
+        sheet_control.sheets[sheet_id].moveto((120, 110));
+        let sheet_area = sheet_control.sheets[sheet_id].area();
+        let sheet_height = sheet_control.sheets[sheet_id].height as isize;
+        sheet_control.refresh_sheet_map(Some(sheet_area), Some(sheet_height));
+        sheet_control.refresh_screen(Some(sheet_area), Some(sheet_height));
+        sheet_id
+    };
+
+    // this task never exits, so the FIFO can live forever
+    let fifo: &'static Mutex<FIFO<Event>> = Box::leak(Box::new(Mutex::new(FIFO::new(128))));
+    let task_id = task::current();
+    asm::cli();
+    fifo.lock().set_task(Some(task_id));
+    fifo::EVENT_ROUTER.lock().register(sheet_id, fifo);
+    asm::sti();
+    let timer = timer::Timer::new(fifo, 0).unwrap();
+    timer.set_periodic(1);
+
+    let mut count: u64 = 0;
+    loop {
+        asm::cli();
+        let fifo_pop_result = fifo.lock().pop();
+        if fifo_pop_result.is_err() {
+            // sleep until something is pushed to the FIFO
+            task::sleep(task_id);
+            asm::sti();
+            continue;
+        }
+        asm::sti();
+
+        match fifo_pop_result {
+            // count up once per tick
+            Ok(Event::Timer(..)) => {
+                count += 1;
+                let mut sheet_control = SHEET_CONTROL.lock();
+                let initial_column_position =
+                    sheet_control.sheets[sheet_id].initial_column_position;
+                sheet_control.sheets[sheet_id].column_position = initial_column_position;
+                sheet_control.sheets[sheet_id]
+                    .boxfill(Color::LightGrey, ((3, 23), (3 + 8 * 17, 23 + 16)));
+                write!(sheet_control.sheets[sheet_id], "{:>017}", count).unwrap();
+                let sheet_height = sheet_control.sheets[sheet_id].height as isize;
+                sheet_control.flush_printed_chars(Some(sheet_height));
+            }
+            Ok(Event::Key(DecodedKey::Unicode('\t'))) => {
+                asm::cli();
+                fifo::EVENT_ROUTER.lock().focus_next();
+                asm::sti();
+            }
+            // show the last key typed while this sheet is focused
+            Ok(Event::Key(key)) => {
+                let mut sheet_control = SHEET_CONTROL.lock();
+                let initial_column_position =
+                    sheet_control.sheets[sheet_id].initial_column_position;
+                sheet_control.sheets[sheet_id].column_position = initial_column_position;
+                sheet_control.sheets[sheet_id]
+                    .boxfill(Color::LightGrey, ((3, 23 + 16), (3 + 8 * 17, 23 + 16 * 2)));
+                write!(
+                    sheet_control.sheets[sheet_id],
+                    "\nkey: {}",
+                    match key {
+                        DecodedKey::Unicode(character) => character,
+                        DecodedKey::RawKey(_) => '?',
+                    }
+                )
+                .unwrap();
+                let sheet_height = sheet_control.sheets[sheet_id].height as isize;
+                sheet_control.flush_printed_chars(Some(sheet_height));
+            }
+            _ => {}
+        }
+    }
+}
@@ -49,6 +49,12 @@ fn panic(info: &PanicInfo) -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use lib::backtrace::Backtrace;
+    use lib::serial_println;
+    // set if walking the stack caused another panic
+    static PANICKED: AtomicBool = AtomicBool::new(false);
+
     lib::asm::cli();
     use vga::writers::{Text80x25, TextWriter};
     let textmode = Text80x25::new();
@@ -57,5 +63,11 @@ fn panic(info: &PanicInfo) -> ! {
         println!();
     }
     println!("{}", info);
+    serial_println!("{}", info);
+    if !PANICKED.swap(true, Ordering::Relaxed) {
+        let backtrace = Backtrace::capture();
+        println!("{}", backtrace);
+        serial_println!("{}", backtrace);
+    }
     haribote::hlt_loop();
 }
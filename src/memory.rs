@@ -1,10 +1,25 @@
+use crate::asm;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::rflags;
 use x86_64::{
     registers::control::Cr3,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB,
+    },
     PhysAddr, VirtAddr,
 };
 
+/// per-task virtual address spaces
+pub mod address_space;
+/// lazily-backed regions mapped on page faults
+pub mod demand;
+/// kernel stacks of tasks with guard pages
+pub mod stack;
+
 /// Returns a mutable reference to the active level 4 table.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -20,10 +35,114 @@ unsafe fn active_level4_table(physical_memory_offset: VirtAddr) -> &'static mut
 }
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_LEVEL_4_TABLE.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
     let level_4_page_table = active_level4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_page_table, physical_memory_offset)
 }
 
+/// The virtual address where the complete physical memory is mapped, set by `init`.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// The physical address of the level 4 table active when `init` is called.
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Whether `address` is mapped in the active page table. This reads the page tables without
+/// locking `MAPPER`, so that exception handlers can check an address before reading it.
+/// Returns false before `init` is called.
+pub fn is_mapped(address: VirtAddr) -> bool {
+    let physical_memory_offset = physical_memory_offset();
+    if physical_memory_offset.as_u64() == 0 {
+        return false;
+    }
+    let indices = [
+        address.p4_index(),
+        address.p3_index(),
+        address.p2_index(),
+        address.p1_index(),
+    ];
+    let mut table_address = Cr3::read().0.start_address();
+    for (level, &index) in indices.iter().enumerate() {
+        let table: &PageTable =
+            unsafe { &*(physical_memory_offset + table_address.as_u64()).as_ptr() };
+        let flags = table[index].flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        // 1GiB and 2MiB pages end the walk
+        if level < 3 && flags.contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        table_address = table[index].addr();
+    }
+    true
+}
+
+/// The frame of the level 4 table of the kernel, which is used by tasks without their own
+/// address space.
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed)))
+}
+
+/// The page table and the frame allocator of the kernel, set by `init_global`.
+/// They are locked in the page fault handler, so use `with_mapper` to lock them after `cli`.
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+/// Make the page table returned by `init` and the frame allocator available through `with_mapper`
+/// after the initialization of the kernel.
+pub fn init_global(mapper: OffsetPageTable<'static>, frame_allocator: BitmapFrameAllocator) {
+    let rf = rflags::read();
+    asm::cli();
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    rflags::write(rf);
+}
+
+/// Call `f` with the page table and the frame allocator of the kernel.
+/// Panics if `init_global` is not called yet.
+pub fn with_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+) -> R {
+    let rf = rflags::read();
+    asm::cli();
+    let result = {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) => Some(f(mapper, frame_allocator)),
+            _ => None,
+        }
+    };
+    rflags::write(rf);
+    result.expect("memory is not initialized")
+}
+
+/// Call `f` with the page table and the frame allocator of the kernel, or return None if
+/// `init_global` is not called yet or they are already locked.
+/// Unlike `with_mapper`, this never spins, so it can be used by the heap allocator and the page
+/// fault handler, which may be called inside a closure of `with_mapper`.
+pub fn try_with_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+) -> Option<R> {
+    let rf = rflags::read();
+    asm::cli();
+    let result = match (MAPPER.try_lock(), FRAME_ALLOCATOR.try_lock()) {
+        (Some(mut mapper), Some(mut frame_allocator)) => {
+            match (mapper.as_mut(), frame_allocator.as_mut()) {
+                (Some(mapper), Some(frame_allocator)) => Some(f(mapper, frame_allocator)),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+    rflags::write(rf);
+    result
+}
+
 pub struct EmptyFrameAllocator;
 
 unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
@@ -32,40 +151,225 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
+const PAGE_SIZE: u64 = 4096;
+
+/// A FrameAllocator managing the usable frames in the bootloader's memory map with a bitmap, in
+/// which the bit of a frame is set if the frame is free.
+/// The bitmap itself is placed at the start of the first usable region large enough to hold it.
+pub struct BitmapFrameAllocator {
     memory_map: &'static MemoryMap,
+    bitmap: &'static mut [u64],
+    /// Frame numbers occupied by the bitmap, which are never allocated.
+    bitmap_frames: Range<u64>,
+    /// The number of usable frames, including the ones occupied by the bitmap.
+    total_frames: usize,
+    free_frames: usize,
+    /// The word of the bitmap where the search for a free frame starts.
     next: usize,
 }
 
-/// Create a FrameAllocator from the passed memory map.
-///
-/// This function is unsafe because the caller must guarantee that the passed
-/// memory map is valid. The main requirement is that all frames that are marked
-/// as `USABLE` in it are really unused.
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+impl BitmapFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid and the complete physical memory is mapped at the passed
+    /// `physical_memory_offset`. The main requirement is that all frames that are marked
+    /// as `USABLE` in it are really unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+        let end_frame = usable_regions()
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0);
+        let words = (end_frame as usize + 63) / 64;
+        let bitmap_size = (words as u64 * 8 + PAGE_SIZE - 1) / PAGE_SIZE;
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_size)
+            .expect("no usable region can hold the frame bitmap")
+            .range
+            .start_frame_number;
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start * PAGE_SIZE).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, words);
+        for word in bitmap.iter_mut() {
+            *word = 0;
+        }
+
+        let mut allocator = Self {
             memory_map,
+            bitmap,
+            bitmap_frames: bitmap_start..bitmap_start + bitmap_size,
+            total_frames: 0,
+            free_frames: 0,
             next: 0,
+        };
+        for region in usable_regions() {
+            for frame in region.range.start_frame_number..region.range.end_frame_number {
+                allocator.total_frames += 1;
+                if !allocator.bitmap_frames.contains(&frame) {
+                    allocator.set_free(frame as usize, true);
+                }
+            }
         }
+        allocator
+    }
+
+    fn is_free(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & 1 << (index % 64) != 0
+    }
+
+    /// Mark the frame as free or allocated. The state must be changed by this.
+    fn set_free(&mut self, index: usize, free: bool) {
+        if free {
+            self.bitmap[index / 64] |= 1 << (index % 64);
+            self.free_frames += 1;
+        } else {
+            self.bitmap[index / 64] &= !(1 << (index % 64));
+            self.free_frames -= 1;
+        }
+    }
+
+    /// Whether the frame is managed by this allocator, i.e. can be allocated and deallocated.
+    fn is_managed(&self, index: usize) -> bool {
+        let frame = index as u64;
+        !self.bitmap_frames.contains(&frame)
+            && self.memory_map.iter().any(|r| {
+                r.region_type == MemoryRegionType::Usable
+                    && r.range.start_frame_number <= frame
+                    && frame < r.range.end_frame_number
+            })
+    }
+
+    fn frame(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * PAGE_SIZE))
+    }
+
+    /// Allocate `count` physically contiguous frames, e.g. for DMA buffers.
+    /// Returns None if there are not so many contiguous free frames.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        assert!(count > 0);
+        let frames = self.bitmap.len() * 64;
+        let (mut start, mut len) = (0, 0);
+        let mut index = 0;
+        while index < frames {
+            // skip words with no free frame at once
+            if index % 64 == 0 && self.bitmap[index / 64] == 0 {
+                len = 0;
+                index += 64;
+                continue;
+            }
+            if !self.is_free(index) {
+                len = 0;
+            } else {
+                if len == 0 {
+                    start = index;
+                }
+                len += 1;
+                if len == count {
+                    for i in start..start + count {
+                        self.set_free(i, false);
+                    }
+                    return Some(PhysFrame::range(
+                        Self::frame(start),
+                        Self::frame(start + count),
+                    ));
+                }
+            }
+            index += 1;
+        }
+        None
+    }
+
+    /// Free the frames allocated by `allocate_contiguous`.
+    ///
+    /// This function is unsafe because the caller must guarantee that the frames are not used
+    /// anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, frames: PhysFrameRange) {
+        for frame in frames {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    /// Size of the usable memory in bytes.
+    pub fn total_memory(&self) -> u64 {
+        self.total_frames as u64 * PAGE_SIZE
     }
-}
 
-impl BootInfoFrameAllocator {
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    /// Size of the free memory in bytes.
+    pub fn free_memory(&self) -> u64 {
+        self.free_frames as u64 * PAGE_SIZE
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        let words = self.bitmap.len();
+        for i in 0..words {
+            let word_index = (self.next + i) % words;
+            let word = self.bitmap[word_index];
+            if word != 0 {
+                let index = word_index * 64 + word.trailing_zeros() as usize;
+                self.set_free(index, false);
+                self.next = word_index;
+                return Some(Self::frame(index));
+            }
+        }
+        None
     }
 }
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / PAGE_SIZE) as usize;
+        assert!(
+            self.is_managed(index),
+            "{:?} is not managed by the frame allocator",
+            frame
+        );
+        assert!(!self.is_free(index), "{:?} is freed twice", frame);
+        self.set_free(index, true);
+    }
+}
+
+#[test_case]
+fn test_frame_allocator() {
+    with_mapper(|_, frame_allocator| {
+        let free = frame_allocator.free_memory();
+        assert!(free <= frame_allocator.total_memory());
+
+        let frame = frame_allocator.allocate_frame().unwrap();
+        assert_eq!(frame_allocator.free_memory(), free - PAGE_SIZE);
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        assert_eq!(frame_allocator.free_memory(), free);
+
+        let frames = frame_allocator.allocate_contiguous(3).unwrap();
+        assert_eq!(frames.end - frames.start, 3);
+        assert_eq!(frame_allocator.free_memory(), free - PAGE_SIZE * 3);
+        // frames in the range are not allocated again
+        let frame = frame_allocator.allocate_frame().unwrap();
+        assert!(frame < frames.start || frames.end <= frame);
+        unsafe {
+            frame_allocator.deallocate_frame(frame);
+            frame_allocator.deallocate_contiguous(frames);
+        }
+        assert_eq!(frame_allocator.free_memory(), free);
+    });
+}
+
+#[test_case]
+fn test_try_with_mapper_nested() {
+    // the heap can't grow inside `with_mapper`, but doesn't deadlock
+    with_mapper(|_, _| assert!(try_with_mapper(|_, _| ()).is_none()));
+    assert!(try_with_mapper(|_, _| ()).is_some());
+}
+
+#[test_case]
+fn test_is_mapped() {
+    let on_stack = 0u64;
+    assert!(is_mapped(VirtAddr::new(&on_stack as *const u64 as u64)));
+    assert!(is_mapped(VirtAddr::new(test_is_mapped as usize as u64)));
+    assert!(!is_mapped(VirtAddr::new(0xdead_beaf_000)));
+}
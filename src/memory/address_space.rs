@@ -0,0 +1,182 @@
+use super::{physical_memory_offset, with_mapper, BitmapFrameAllocator};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PageTableEntry, PageTableFlags,
+    PhysFrame,
+};
+
+// The bootloader maps the kernel, its stack and the physical memory in the lower half, and so do
+// the heap and the other areas of the kernel. Therefore the lower half is the kernel half shared by
+// every address space, and the upper half is private to each address space.
+
+/// The number of level 4 entries of the kernel half.
+const KERNEL_ENTRIES: usize = 256;
+
+/// The start of the private half of address spaces.
+pub const USER_START: u64 = 0xffff_8000_0000_0000;
+
+/// Set once every entry of the kernel half of the kernel's level 4 table is present.
+static KERNEL_HALF_SHARED: AtomicBool = AtomicBool::new(false);
+
+/// Returns a mutable reference to the page table in `frame`.
+///
+/// This function is unsafe because the caller must guarantee that `frame` holds a page table
+/// and no other reference to it is alive.
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    let virt = physical_memory_offset() + frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr()
+}
+
+/// Fill every empty entry of the kernel half with an empty level 3 table. Address spaces copy
+/// the entries, so that pages mapped in the kernel half later are seen by every address space.
+/// Returns false if no frame is available, in which case the entries filled so far are kept and
+/// the rest are filled by the next call.
+fn share_kernel_half() -> bool {
+    if KERNEL_HALF_SHARED.load(Ordering::Acquire) {
+        return true;
+    }
+    with_mapper(|mapper, frame_allocator| {
+        let level_4_table = mapper.level_4_table();
+        for entry in level_4_table.iter_mut().take(KERNEL_ENTRIES) {
+            if !entry.is_unused() {
+                continue;
+            }
+            let frame = match frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            unsafe { table_at(frame) }.zero();
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+        KERNEL_HALF_SHARED.store(true, Ordering::Release);
+        true
+    })
+}
+
+/// A level 4 table sharing the kernel half with the kernel's table, with its own upper half.
+/// The tables and the frames mapped in the upper half are freed when this is dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Create an address space whose upper half is empty.
+    /// Returns None if no frame is available.
+    pub fn new() -> Option<Self> {
+        if !share_kernel_half() {
+            return None;
+        }
+        with_mapper(|mapper, frame_allocator| {
+            let level_4_frame = frame_allocator.allocate_frame()?;
+            let level_4_table = unsafe { table_at(level_4_frame) };
+            level_4_table.zero();
+            let kernel_table = mapper.level_4_table();
+            for i in 0..KERNEL_ENTRIES {
+                level_4_table[i] = kernel_table[i].clone();
+            }
+            Some(Self { level_4_frame })
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Call `f` with the page table of this address space and the frame allocator of the kernel,
+    /// e.g. to map pages of a task. Pages are to be mapped in the upper half, from `USER_START`,
+    /// and backed by frames from the frame allocator, which are freed on drop.
+    pub fn with_mapper<R>(
+        &mut self,
+        f: impl FnOnce(&mut OffsetPageTable, &mut BitmapFrameAllocator) -> R,
+    ) -> R {
+        let level_4_frame = self.level_4_frame;
+        with_mapper(|_, frame_allocator| {
+            let level_4_table = unsafe { table_at(level_4_frame) };
+            let mut mapper =
+                unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset()) };
+            f(&mut mapper, frame_allocator)
+        })
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(
+            Cr3::read().0 != self.level_4_frame,
+            "the active address space is dropped"
+        );
+        let level_4_frame = self.level_4_frame;
+        with_mapper(|_, frame_allocator| unsafe {
+            for entry in table_at(level_4_frame).iter().skip(KERNEL_ENTRIES) {
+                free_entry(entry, 3, frame_allocator);
+            }
+            frame_allocator.deallocate_frame(level_4_frame);
+        });
+    }
+}
+
+/// Free the frame `entry` points to, which is a page table of `level` or a mapped page if `level`
+/// is 0, and the frames under it.
+unsafe fn free_entry(
+    entry: &PageTableEntry,
+    level: usize,
+    frame_allocator: &mut BitmapFrameAllocator,
+) {
+    // huge pages are skipped, since they are not backed by frames of the frame allocator
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    if level > 0 {
+        for entry in table_at(frame).iter() {
+            free_entry(entry, level - 1, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(frame);
+}
+
+/// Load the level 4 table in `frame` to CR3 unless it is already active.
+///
+/// This function is unsafe because the caller must guarantee that `frame` holds a level 4 table
+/// sharing the kernel half, e.g. the kernel's one or the one of an `AddressSpace` alive.
+pub unsafe fn switch_to(frame: PhysFrame) {
+    let (active, flags) = Cr3::read();
+    if active != frame {
+        Cr3::write(frame, flags);
+    }
+}
+
+#[test_case]
+fn test_address_space() {
+    use x86_64::structures::paging::{Mapper, MapperAllSizes, Page, Size4KiB};
+    use x86_64::VirtAddr;
+
+    assert!(share_kernel_half());
+    let free_memory = || with_mapper(|_, frame_allocator| frame_allocator.free_memory());
+    let free = free_memory();
+
+    let address = VirtAddr::new(USER_START);
+    let mut address_space = AddressSpace::new().unwrap();
+    address_space.with_mapper(|mapper, frame_allocator| {
+        let frame = frame_allocator.allocate_frame().unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let page = Page::<Size4KiB>::containing_address(address);
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+            .unwrap()
+            .flush();
+    });
+    // the page is mapped only in the address space
+    assert!(with_mapper(|mapper, _| mapper.translate_addr(address)).is_none());
+    unsafe {
+        switch_to(address_space.level_4_frame());
+        *address.as_mut_ptr::<u64>() = 42;
+        assert_eq!(*address.as_ptr::<u64>(), 42);
+        switch_to(super::kernel_level_4_frame());
+    }
+
+    // the level 4 table, the level 3, 2 and 1 tables and the page are freed
+    drop(address_space);
+    assert_eq!(free_memory(), free);
+}
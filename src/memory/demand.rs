@@ -0,0 +1,191 @@
+use super::{try_with_mapper, with_mapper};
+use crate::asm;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::registers::rflags;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// Virtual area where lazily-backed regions are reserved.
+const DEMAND_AREA_START: u64 = 0x_6666_0000_0000;
+const DEMAND_AREA_END: u64 = 0x_6676_0000_0000;
+const PAGE_SIZE: u64 = 4096;
+
+/// A range of virtual memory whose pages are mapped when they are touched for the first time.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    start: u64,
+    end: u64,
+}
+
+/// Regions reserved by `reserve`, and the start of the next region.
+/// The regions are locked in the page fault handler, so lock them after `cli`.
+struct DemandRegions {
+    regions: Vec<Region>,
+    next: u64,
+}
+
+static DEMAND_REGIONS: Mutex<DemandRegions> = Mutex::new(DemandRegions {
+    regions: Vec::new(),
+    next: DEMAND_AREA_START,
+});
+
+/// Reserve `size` bytes of virtual memory without mapping any page.
+/// Each page is backed by a zero-filled frame on the first access.
+/// Returns None if the virtual area for lazily-backed regions is exhausted.
+pub fn reserve(size: usize) -> Option<VirtAddr> {
+    let size = (size as u64).saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let rf = rflags::read();
+    asm::cli();
+    let start = {
+        let mut demand_regions = DEMAND_REGIONS.lock();
+        let start = demand_regions.next;
+        // leave an unmapped page between regions so that overruns fault
+        if size.saturating_add(PAGE_SIZE) > DEMAND_AREA_END - start {
+            None
+        } else {
+            let end = start + size;
+            demand_regions.regions.push(Region { start, end });
+            demand_regions.next = end + PAGE_SIZE;
+            Some(start)
+        }
+    };
+    rflags::write(rf);
+    start.map(VirtAddr::new)
+}
+
+/// Unmap the pages of the region reserved at `start`, free their frames and forget the region.
+pub fn release(start: VirtAddr) {
+    let rf = rflags::read();
+    asm::cli();
+    let region = {
+        let mut demand_regions = DEMAND_REGIONS.lock();
+        let index = demand_regions
+            .regions
+            .iter()
+            .position(|region| region.start == start.as_u64());
+        index.map(|index| demand_regions.regions.swap_remove(index))
+    };
+    if let Some(region) = region {
+        with_mapper(|mapper, frame_allocator| {
+            let first = Page::<Size4KiB>::containing_address(VirtAddr::new(region.start));
+            let last = Page::containing_address(VirtAddr::new(region.end - 1));
+            for page in Page::range_inclusive(first, last) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        });
+    }
+    rflags::write(rf);
+}
+
+/// Map a frame to the page at `address` if it is in a lazily-backed region and not mapped yet.
+/// Returns false if the fault can't be resolved. This includes faults raised while the regions or
+/// the page table are locked, e.g. by a closure of `with_mapper` touching a region, since locking
+/// them again would deadlock.
+/// This function is only to be called by the page fault handler.
+pub fn handle_page_fault(address: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let in_region = match DEMAND_REGIONS.try_lock() {
+        Some(demand_regions) => demand_regions
+            .regions
+            .iter()
+            .any(|region| region.start <= address.as_u64() && address.as_u64() < region.end),
+        None => false,
+    };
+    if !in_region {
+        return false;
+    }
+    try_with_mapper(|mapper, frame_allocator| {
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        // clear the frame through the mapping of the physical memory, not to leak old data
+        let frame_ptr: *mut u8 =
+            (super::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe {
+            core::ptr::write_bytes(frame_ptr, 0, PAGE_SIZE as usize);
+        }
+        let page = Page::<Size4KiB>::containing_address(address);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => false,
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// A buffer in a lazily-backed region, e.g. for large pixel buffers most of which are untouched.
+/// The region is released when the buffer is dropped.
+pub struct DemandBuffer {
+    start: VirtAddr,
+    len: usize,
+}
+
+impl DemandBuffer {
+    /// Reserve a zero-filled buffer of `len` bytes.
+    pub fn new(len: usize) -> Option<Self> {
+        Some(Self {
+            start: reserve(len)?,
+            len,
+        })
+    }
+}
+
+impl core::ops::Deref for DemandBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.start.as_ptr(), self.len) }
+    }
+}
+
+impl core::ops::DerefMut for DemandBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.start.as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DemandBuffer {
+    fn drop(&mut self) {
+        release(self.start);
+    }
+}
+
+#[test_case]
+fn test_demand_paging() {
+    use x86_64::structures::paging::MapperAllSizes;
+    let mut buffer = DemandBuffer::new(PAGE_SIZE as usize * 3).unwrap();
+    let start = buffer.start;
+    let is_mapped =
+        |offset: u64| with_mapper(|mapper, _| mapper.translate_addr(start + offset).is_some());
+    assert!(!is_mapped(0));
+
+    // the first touch maps only the touched page, which is zero-filled
+    assert_eq!(buffer[PAGE_SIZE as usize + 1], 0);
+    buffer[PAGE_SIZE as usize + 1] = 42;
+    assert_eq!(buffer[PAGE_SIZE as usize + 1], 42);
+    assert!(!is_mapped(0));
+    assert!(is_mapped(PAGE_SIZE));
+    assert!(!is_mapped(PAGE_SIZE * 2));
+
+    // a fault raised while the page table is locked is not resolved
+    with_mapper(|_, _| assert!(!handle_page_fault(start, PageFaultErrorCode::empty())));
+    assert!(!is_mapped(0));
+
+    drop(buffer);
+    assert!(!is_mapped(PAGE_SIZE));
+    // the region is forgotten, so a fault there is not resolved anymore
+    assert!(!handle_page_fault(start, PageFaultErrorCode::empty()));
+}
@@ -0,0 +1,143 @@
+use super::with_mapper;
+use crate::asm;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::registers::rflags;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+/// Virtual area where kernel stacks are mapped.
+/// The area is divided into slots of `SLOT_PAGES` pages, and each stack is mapped at the top of a
+/// slot. The pages below the stack are never mapped, so they work as a guard.
+const STACK_AREA_START: u64 = 0x_7777_0000_0000;
+const SLOT_PAGES: u64 = 16;
+const MAX_SLOTS: usize = 4096;
+const PAGE_SIZE: u64 = 4096;
+
+/// The maximum size of a stack in pages. At least one page of each slot is left as the guard.
+pub const MAX_STACK_PAGES: usize = SLOT_PAGES as usize - 1;
+
+/// A kernel stack with an unmapped guard page below it.
+/// The stack is unmapped and its frames are freed when this is dropped.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+    pages: u64,
+}
+
+impl KernelStack {
+    /// The address above the highest byte of the stack, i.e. the initial stack pointer.
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(slot_start(self.slot) + SLOT_PAGES * PAGE_SIZE)
+    }
+    /// The lowest address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.top() - self.pages * PAGE_SIZE
+    }
+    /// Whether `address` is in the guard pages of this stack, i.e. an access there means overflow.
+    pub fn is_guard(&self, address: VirtAddr) -> bool {
+        VirtAddr::new(slot_start(self.slot)) <= address && address < self.bottom()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let first = Page::<Size4KiB>::containing_address(self.bottom());
+        let last = Page::containing_address(self.top() - 1u64);
+        with_mapper(|mapper, frame_allocator| {
+            for page in Page::range_inclusive(first, last) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        });
+        let rf = rflags::read();
+        asm::cli();
+        STACK_ALLOCATOR.lock().free.push(self.slot);
+        rflags::write(rf);
+    }
+}
+
+fn slot_start(slot: usize) -> u64 {
+    STACK_AREA_START + slot as u64 * SLOT_PAGES * PAGE_SIZE
+}
+
+/// Slots which have never been used start from `next`, and freed ones are in `free`.
+struct StackAllocator {
+    next: usize,
+    free: Vec<usize>,
+}
+
+static STACK_ALLOCATOR: Mutex<StackAllocator> = Mutex::new(StackAllocator {
+    next: 0,
+    free: Vec::new(),
+});
+
+/// Map a kernel stack of `pages` pages in the stack area.
+/// Returns None if no slot or no frame is available.
+pub fn allocate(pages: usize) -> Option<KernelStack> {
+    assert!(0 < pages && pages <= MAX_STACK_PAGES);
+    let rf = rflags::read();
+    asm::cli();
+    let slot = {
+        let mut stack_allocator = STACK_ALLOCATOR.lock();
+        match stack_allocator.free.pop() {
+            Some(slot) => Some(slot),
+            None if stack_allocator.next < MAX_SLOTS => {
+                stack_allocator.next += 1;
+                Some(stack_allocator.next - 1)
+            }
+            None => None,
+        }
+    };
+    rflags::write(rf);
+
+    // dropping the stack on failure unmaps the pages mapped so far and frees the slot
+    let stack = KernelStack {
+        slot: slot?,
+        pages: pages as u64,
+    };
+    map_stack(&stack).ok()?;
+    Some(stack)
+}
+
+fn map_stack(stack: &KernelStack) -> Result<(), MapToError<Size4KiB>> {
+    let first = Page::<Size4KiB>::containing_address(stack.bottom());
+    let last = Page::containing_address(stack.top() - 1u64);
+    with_mapper(|mapper, frame_allocator| {
+        for page in Page::range_inclusive(first, last) {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            unsafe {
+                mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            }
+        }
+        Ok(())
+    })
+}
+
+#[test_case]
+fn test_kernel_stack() {
+    use x86_64::structures::paging::MapperAllSizes;
+    let stack = allocate(2).unwrap();
+    let is_mapped =
+        |address: VirtAddr| with_mapper(|mapper, _| mapper.translate_addr(address).is_some());
+    assert_eq!(stack.top() - stack.bottom(), 2 * PAGE_SIZE);
+    assert!(is_mapped(stack.top() - 8u64));
+    assert!(is_mapped(stack.bottom()));
+    assert!(!is_mapped(stack.bottom() - 1u64));
+    assert!(stack.is_guard(stack.bottom() - 1u64));
+    assert!(!stack.is_guard(stack.bottom()));
+
+    let top = stack.top();
+    unsafe {
+        *(top - 8u64).as_mut_ptr::<u64>() = 42;
+    }
+    drop(stack);
+    assert!(!is_mapped(top - 8u64));
+}
@@ -0,0 +1,191 @@
+use crate::asm;
+use core::fmt;
+use x86_64::instructions::port::{Port, PortWriteOnly};
+use x86_64::registers::rflags;
+
+const CMOS_ADDRESS: u16 = 0x0070;
+const CMOS_DATA: u16 = 0x0071;
+
+// registers of CMOS
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+/// Set in status register A while the RTC is updating the time.
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Set in status register B if the RTC raises the update-ended interrupt.
+const STATUS_B_UPDATE_INTERRUPT: u8 = 1 << 4;
+/// Set in status register B if the time is binary, otherwise BCD.
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Set in status register B if the hour is in 24-hour format, otherwise 12-hour format.
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Set in the hour register for PM in 12-hour format.
+const HOUR_PM: u8 = 1 << 7;
+
+/// Wall-clock time read from the RTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Read the current date and time.
+pub fn read() -> DateTime {
+    let rf = rflags::read();
+    asm::cli();
+    // read until the same time is read twice in a row, since an update can happen in the middle
+    let mut registers = read_registers();
+    loop {
+        let again = read_registers();
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+    let status_b = read_cmos(REG_STATUS_B);
+    rflags::write(rf);
+    decode(registers, status_b)
+}
+
+/// Let the RTC raise IRQ8 every second when it finishes updating the time.
+/// The interrupt handler pushes `Event::Rtc` to `GLOBAL_FIFO_BUF`.
+pub fn init() {
+    let rf = rflags::read();
+    asm::cli();
+    let status_b = read_cmos(REG_STATUS_B);
+    write_cmos(REG_STATUS_B, status_b | STATUS_B_UPDATE_INTERRUPT);
+    // discard the interrupt pending from before
+    acknowledge_interrupt();
+    if !crate::apic::is_enabled() {
+        // unmask IRQ8 of the slave PIC and IRQ2 of the master PIC, to which the slave is connected
+        unsafe {
+            let mut slave_mask = Port::<u8>::new(0x00a1);
+            let mask = slave_mask.read();
+            slave_mask.write(mask & !(1 << 0));
+            let mut master_mask = Port::<u8>::new(0x0021);
+            let mask = master_mask.read();
+            master_mask.write(mask & !(1 << 2));
+        }
+    }
+    rflags::write(rf);
+}
+
+/// Read status register C, without which the RTC never raises the interrupt again.
+/// This function is only to be called by `interrupts.rs` and `init`.
+pub fn acknowledge_interrupt() {
+    read_cmos(REG_STATUS_C);
+}
+
+/// Second, minute, hour, day, month and year as they are in the registers.
+fn read_registers() -> [u8; 6] {
+    // wait until the update finishes
+    while read_cmos(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    [
+        read_cmos(REG_SECOND),
+        read_cmos(REG_MINUTE),
+        read_cmos(REG_HOUR),
+        read_cmos(REG_DAY),
+        read_cmos(REG_MONTH),
+        read_cmos(REG_YEAR),
+    ]
+}
+
+/// Convert the values of the registers into `DateTime` according to the format in `status_b`.
+fn decode(registers: [u8; 6], status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year] = registers;
+    let pm = hour & HOUR_PM != 0;
+    let hour = hour & !HOUR_PM;
+    let to_binary = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0x0f)
+        }
+    };
+    let mut hour = to_binary(hour);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12AM is 0 o'clock and 12PM is 12 o'clock
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    DateTime {
+        // the century register is not standard, so assume the 21st century
+        year: 2000 + to_binary(year) as u16,
+        month: to_binary(month),
+        day: to_binary(day),
+        hour,
+        minute: to_binary(minute),
+        second: to_binary(second),
+    }
+}
+
+fn read_cmos(register: u8) -> u8 {
+    let mut port_address = PortWriteOnly::new(CMOS_ADDRESS);
+    let mut port_data = Port::new(CMOS_DATA);
+    unsafe {
+        port_address.write(register);
+        port_data.read()
+    }
+}
+
+fn write_cmos(register: u8, value: u8) {
+    let mut port_address = PortWriteOnly::new(CMOS_ADDRESS);
+    let mut port_data = Port::new(CMOS_DATA);
+    unsafe {
+        port_address.write(register);
+        port_data.write(value);
+    }
+}
+
+#[test_case]
+fn test_decode() {
+    let time = DateTime {
+        year: 2021,
+        month: 3,
+        day: 14,
+        hour: 15,
+        minute: 9,
+        second: 26,
+    };
+    // BCD, 24-hour
+    let bcd = [0x26, 0x09, 0x15, 0x14, 0x03, 0x21];
+    assert_eq!(decode(bcd, STATUS_B_24_HOUR), time);
+    // binary, 12-hour
+    let binary = [26, 9, 3 | HOUR_PM, 14, 3, 21];
+    assert_eq!(decode(binary, STATUS_B_BINARY), time);
+    // BCD, 12-hour, 12AM
+    let midnight = decode([0x00, 0x00, 0x12, 0x01, 0x01, 0x00], 0);
+    assert_eq!(midnight.hour, 0);
+}
+
+#[test_case]
+fn test_read() {
+    let time = read();
+    assert!(1 <= time.month && time.month <= 12);
+    assert!(1 <= time.day && time.day <= 31);
+    assert!(time.hour < 24 && time.minute < 60 && time.second < 60);
+}
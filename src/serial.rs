@@ -22,6 +22,18 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Print to the serial port unless it is locked, and return whether printed.
+/// This never spins, so the handlers of NMIs and debug exceptions can use this even if the code
+/// they interrupted is printing, in which case the output is dropped.
+pub fn try_print(args: ::core::fmt::Arguments) -> bool {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| match SERIAL1.try_lock() {
+        Some(mut serial) => serial.write_fmt(args).is_ok(),
+        None => false,
+    })
+}
+
 #[macro_export]
 macro_rules! serial_print {
         ($($arg:tt)*) => (
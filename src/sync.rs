@@ -0,0 +1,93 @@
+use crate::asm;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+use x86_64::registers::rflags::{self, RFlags};
+
+/// A spin lock which disables interrupts while it is held, like the `cli` before locking FIFOs,
+/// but without the need to remember it.
+///
+/// A task holding the lock is never preempted, so tasks on different levels can share it. With a
+/// plain `spin::Mutex`, a task on a higher level spinning on the lock held by a task on a lower
+/// level would never let the holder run again.
+/// Do not wait for interrupts, e.g. sleep or `hlt`, while holding it.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            inner: Mutex::new(data),
+        }
+    }
+    /// Disable interrupts and lock. Interrupts are restored when the guard is dropped.
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let rflags = rflags::read();
+        asm::cli();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            rflags,
+        }
+    }
+    /// Lock if not locked yet, without spinning.
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let rflags = rflags::read();
+        asm::cli();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                rflags,
+            }),
+            None => {
+                rflags::write(rflags);
+                None
+            }
+        }
+    }
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    /// RFLAGS before `lock`, restored after unlocking.
+    rflags: RFlags,
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // unlock before enabling interrupts, or this task could be preempted holding the lock
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        rflags::write(self.rflags);
+    }
+}
+
+#[test_case]
+fn test_irq_mutex() {
+    use x86_64::instructions::interrupts;
+    let mutex = IrqMutex::new(0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = mutex.lock();
+        assert!(!interrupts::are_enabled());
+        assert!(mutex.try_lock().is_none());
+        // a failed `try_lock` keeps interrupts disabled for the guard
+        assert!(!interrupts::are_enabled());
+        *guard += 1;
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*mutex.try_lock().unwrap(), 1);
+    assert!(interrupts::are_enabled());
+}
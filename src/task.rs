@@ -0,0 +1,667 @@
+use crate::asm;
+use crate::memory::address_space::{self, AddressSpace};
+use crate::memory::stack::{self, KernelStack};
+use crate::timer::TIMER_CONTROL;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::registers::rflags;
+
+const MAX_TASKS: usize = 1000;
+/// The maximum number of running tasks in each level.
+const MAX_TASKS_LV: usize = 100;
+/// The number of task levels. Tasks in level 0 have the highest precedence.
+pub const MAX_TASKLEVELS: usize = 10;
+/// Size of the kernel stack given to each task in pages.
+const TASK_STACK_PAGES: usize = 4;
+/// Default priority, i.e. time slice of tasks in ticks. 0.02s at the default frequency.
+const DEFAULT_PRIORITY: u32 = 2;
+
+// Context switch.
+// Callee-saved registers and RFLAGS are pushed onto the stack of the current task, and its stack
+// pointer is saved to `*old_rsp` (rdi). Then the stack is switched to `new_rsp` (rsi) and the
+// registers of the next task are popped in the reverse order.
+global_asm!(
+    r#"
+.intel_syntax noprefix
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    mov [rdi], rsp
+    mov rsp, rsi
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+.global task_trampoline
+task_trampoline:
+    mov rdi, r12
+    call task_entry
+    ud2
+.att_syntax prefix
+"#
+);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn task_trampoline();
+}
+
+/// The first function called by a newly created task.
+/// `task_trampoline` passes the entry function of the task saved in r12.
+#[no_mangle]
+extern "C" fn task_entry(entry: fn() -> !) -> ! {
+    entry()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Unused,
+    Allocated,
+    Running,
+}
+
+pub struct Task {
+    /// Saved stack pointer. Other registers are saved on the stack of the task itself.
+    rsp: u64,
+    pub flag: TaskState,
+    /// The level this task belongs to.
+    pub level: usize,
+    /// Time slice of this task in ticks.
+    pub priority: u32,
+    /// The number of ticks during which this task owned the CPU.
+    pub ticks: u64,
+    /// Address of the entry function, used to identify the task in reports. 0 for the first task.
+    entry: u64,
+    /// Kernel stack of this task. The task calling `init` keeps using the current stack.
+    stack: Option<KernelStack>,
+    /// Private address space of this task, or None if the task uses the kernel's one.
+    address_space: Option<AddressSpace>,
+}
+
+impl Task {
+    pub fn new() -> Self {
+        Self {
+            rsp: 0,
+            flag: TaskState::Unused,
+            level: 0,
+            priority: DEFAULT_PRIORITY,
+            ticks: 0,
+            entry: 0,
+            stack: None,
+            address_space: None,
+        }
+    }
+    /// Allocate the kernel stack and build the initial frame so that `switch_context` "returns"
+    /// into `entry`. The stack is kept after the task exits and reused when it is allocated again.
+    /// Returns None if the stack can't be allocated.
+    fn prepare(&mut self, entry: fn() -> !) -> Option<()> {
+        if self.stack.is_none() {
+            self.stack = Some(stack::allocate(TASK_STACK_PAGES)?);
+        }
+        // the top of the stack is page-aligned, so rsp is 16-byte aligned after the frame below
+        // is popped
+        let stack_top = self.stack.as_ref().unwrap().top().as_u64();
+        let frame: [u64; 8] = [
+            0x202,                           // RFLAGS (IF enabled)
+            0,                               // r15
+            0,                               // r14
+            0,                               // r13
+            entry as u64,                    // r12
+            0,                               // rbx
+            0,                               // rbp
+            task_trampoline as usize as u64, // return address
+        ];
+        let rsp = stack_top - 16 - 8 * frame.len() as u64;
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len());
+        }
+        self.rsp = rsp;
+        self.entry = entry as u64;
+        Some(())
+    }
+}
+
+/// Running tasks of the same level, executed in round-robin order.
+pub struct TaskLevel {
+    /// Index in `tasks_running` of the task running now.
+    now: usize,
+    /// The index of running tasks (`TaskState::Running`), in the order of execution.
+    tasks_running: Vec<usize>,
+}
+
+impl TaskLevel {
+    pub fn new() -> Self {
+        Self {
+            now: 0,
+            // reserved so that waking tasks in interrupt handlers never allocates memory
+            tasks_running: Vec::with_capacity(MAX_TASKS_LV),
+        }
+    }
+    fn current(&self) -> usize {
+        self.tasks_running[self.now]
+    }
+    fn is_full(&self) -> bool {
+        self.tasks_running.len() >= MAX_TASKS_LV
+    }
+    /// Add the task. The caller must check `is_full` first, so that this never allocates memory.
+    fn add(&mut self, id: usize) {
+        debug_assert!(!self.is_full(), "too many tasks in a level");
+        self.tasks_running.push(id);
+    }
+    fn remove(&mut self, id: usize) {
+        if let Some(i) = self.tasks_running.iter().position(|&task_id| task_id == id) {
+            self.tasks_running.remove(i);
+            // keep `now` pointing to the same task
+            if i < self.now {
+                self.now -= 1;
+            }
+            if self.now >= self.tasks_running.len() {
+                self.now = 0;
+            }
+        }
+    }
+}
+
+/// The reason why `TaskControl::run` failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunError {
+    /// The task index is out of range, or the task has not been allocated.
+    InvalidTask,
+    /// The level is not less than `MAX_TASKLEVELS`.
+    InvalidLevel,
+    /// The level already has `MAX_TASKS_LV` running tasks.
+    LevelFull,
+}
+
+pub struct TaskControl {
+    /// The index of the task which owns the CPU now.
+    /// Note that this task may be already removed from `levels` by `sleep`.
+    current: usize,
+    /// The level running now.
+    now_lv: usize,
+    /// If true, the level to run is searched again at the next task switch.
+    lv_change: bool,
+    levels: Vec<TaskLevel>,
+    pub tasks: Vec<Task>,
+    /// The index of the timer used for task switch.
+    timer_id: usize,
+    /// The index of the idle task, which runs only when no other task is running.
+    idle: usize,
+    /// The index of the first task, i.e. the caller of `init`.
+    main: usize,
+    /// The number of ticks since `init`.
+    total_ticks: u64,
+    /// The number of ticks during which the idle task owned the CPU.
+    idle_ticks: u64,
+    /// The number of ticks and idle ticks in the current load window.
+    window_ticks: u32,
+    window_idle_ticks: u32,
+    /// CPU load (%) of the last load window.
+    load: u32,
+}
+
+lazy_static! {
+    pub static ref TASK_CONTROL: Mutex<TaskControl> = Mutex::new(TaskControl::new());
+}
+
+impl TaskControl {
+    pub fn new() -> Self {
+        let mut tasks = Vec::with_capacity(MAX_TASKS);
+        for _ in 0..MAX_TASKS {
+            tasks.push(Task::new());
+        }
+        let mut levels = Vec::with_capacity(MAX_TASKLEVELS);
+        for _ in 0..MAX_TASKLEVELS {
+            levels.push(TaskLevel::new());
+        }
+        Self {
+            current: 0,
+            now_lv: 0,
+            lv_change: false,
+            levels,
+            tasks,
+            timer_id: 0,
+            idle: 0,
+            main: 0,
+            total_ticks: 0,
+            idle_ticks: 0,
+            window_ticks: 0,
+            window_idle_ticks: 0,
+            load: 0,
+        }
+    }
+    fn find_unused(&mut self) -> Option<usize> {
+        for i in 0..MAX_TASKS {
+            if self.tasks[i].flag == TaskState::Unused {
+                self.tasks[i].flag = TaskState::Allocated;
+                return Some(i);
+            }
+        }
+        return None;
+    }
+    /// Allocate a new task which starts from `entry` when it is switched to for the first time.
+    pub fn allocate(&mut self, entry: fn() -> !) -> Option<usize> {
+        let id = self.find_unused()?;
+        if self.tasks[id].prepare(entry).is_none() {
+            self.tasks[id].flag = TaskState::Unused;
+            return None;
+        }
+        Some(id)
+    }
+    /// Add the task to the running tasks of the given level with the given priority.
+    /// If `level` is None, the level is not changed. If `priority` is 0, the priority is not
+    /// changed.
+    /// Returns an error if the task has not been allocated, or the level doesn't exist or is full.
+    /// Nothing is changed on error.
+    pub fn run(&mut self, id: usize, level: Option<usize>, priority: u32) -> Result<(), RunError> {
+        if id >= MAX_TASKS || self.tasks[id].flag == TaskState::Unused {
+            return Err(RunError::InvalidTask);
+        }
+        let level = level.unwrap_or(self.tasks[id].level);
+        if level >= MAX_TASKLEVELS {
+            return Err(RunError::InvalidLevel);
+        }
+        let moves = self.tasks[id].flag != TaskState::Running || self.tasks[id].level != level;
+        if moves && self.levels[level].is_full() {
+            return Err(RunError::LevelFull);
+        }
+        if priority > 0 {
+            self.tasks[id].priority = priority;
+        }
+        if self.tasks[id].flag == TaskState::Running && self.tasks[id].level != level {
+            // remove from the old level first
+            self.remove(id);
+        }
+        if self.tasks[id].flag != TaskState::Running {
+            self.tasks[id].flag = TaskState::Running;
+            self.tasks[id].level = level;
+            self.levels[level].add(id);
+        }
+        self.lv_change = true;
+        Ok(())
+    }
+    /// Remove the task from the running tasks.
+    fn remove(&mut self, id: usize) {
+        self.levels[self.tasks[id].level].remove(id);
+        self.tasks[id].flag = TaskState::Allocated;
+    }
+    /// The index of the task running now.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+    /// Set `now_lv` to the highest level which has running tasks.
+    fn switch_level(&mut self) {
+        if let Some(lv) = self
+            .levels
+            .iter()
+            .position(|level| !level.tasks_running.is_empty())
+        {
+            self.now_lv = lv;
+        }
+        self.lv_change = false;
+    }
+    /// The task to run next, or None if there are no running tasks.
+    fn next_task(&mut self) -> Option<usize> {
+        if self.lv_change {
+            self.switch_level();
+        }
+        let level = &self.levels[self.now_lv];
+        if level.tasks_running.is_empty() {
+            None
+        } else {
+            Some(level.current())
+        }
+    }
+    /// Make `next` the current task and switch to its address space. Returns the pointer to save
+    /// the stack pointer of the old task and the stack pointer of `next`, which are to be passed to
+    /// `switch_context`.
+    fn prepare_switch(&mut self, next: usize) -> Option<(*mut u64, u64)> {
+        if next == self.current {
+            return None;
+        }
+        let old = self.current;
+        self.current = next;
+        // kernel stacks are in the kernel half shared by every address space, so the stack of the
+        // old task is still usable
+        unsafe { address_space::switch_to(self.level_4_frame(next)) };
+        // `tasks` is never reallocated, so the pointer is still valid after unlocking.
+        Some((&mut self.tasks[old].rsp as *mut u64, self.tasks[next].rsp))
+    }
+    /// The frame of the level 4 table the task runs with.
+    fn level_4_frame(&self, id: usize) -> x86_64::structures::paging::PhysFrame {
+        match &self.tasks[id].address_space {
+            Some(address_space) => address_space.level_4_frame(),
+            None => crate::memory::kernel_level_4_frame(),
+        }
+    }
+    /// Charge one tick to the current task.
+    fn account_tick(&mut self) {
+        let current = self.current;
+        self.tasks[current].ticks += 1;
+        self.total_ticks += 1;
+        self.window_ticks += 1;
+        if current == self.idle {
+            self.idle_ticks += 1;
+            self.window_idle_ticks += 1;
+        }
+        // CPU load is calculated over one second
+        if self.window_ticks >= crate::timer::frequency() {
+            self.load = 100 - self.window_idle_ticks * 100 / self.window_ticks;
+            self.window_ticks = 0;
+            self.window_idle_ticks = 0;
+        }
+    }
+}
+
+/// The task running when nothing else is runnable.
+fn idle_main() -> ! {
+    loop {
+        asm::stihlt();
+    }
+}
+
+/// Register the caller as the first task, create the idle task and start the task switch timer.
+/// Returns the index of the task of the caller.
+pub fn init() -> usize {
+    let rf = rflags::read();
+    asm::cli();
+    let id = {
+        let mut task_control = TASK_CONTROL.lock();
+        let id = task_control.find_unused().unwrap();
+        task_control.run(id, Some(0), DEFAULT_PRIORITY).unwrap();
+        task_control.switch_level();
+        task_control.current = id;
+        task_control.main = id;
+
+        let idle = task_control.allocate(idle_main).unwrap();
+        task_control.run(idle, Some(MAX_TASKLEVELS - 1), 1).unwrap();
+        task_control.idle = idle;
+
+        let mut locked_tc = TIMER_CONTROL.lock();
+        let timer_id = locked_tc.allocate().unwrap();
+        locked_tc.task_timer = Some(timer_id);
+        locked_tc.set_time(timer_id, DEFAULT_PRIORITY);
+        task_control.timer_id = timer_id;
+        id
+    };
+    rflags::write(rf);
+    id
+}
+
+/// Allocate a new task starting from `entry`. The task does not run until `run` is called.
+pub fn allocate(entry: fn() -> !) -> Option<usize> {
+    let rf = rflags::read();
+    asm::cli();
+    let id = TASK_CONTROL.lock().allocate(entry);
+    rflags::write(rf);
+    id
+}
+
+/// Start running the task, or change the level and priority of the running task.
+/// See `TaskControl::run` for the meaning of the arguments and errors.
+pub fn run(id: usize, level: Option<usize>, priority: u32) -> Result<(), RunError> {
+    let rf = rflags::read();
+    asm::cli();
+    let result = TASK_CONTROL.lock().run(id, level, priority);
+    rflags::write(rf);
+    result
+}
+
+/// Wake up the sleeping task. Nothing happens if the task is already running.
+/// This is called by interrupt handlers through FIFOs, so this never panics. If the level of the
+/// task is full, the task keeps sleeping until it is woken up again.
+pub fn wake(id: usize) {
+    let rf = rflags::read();
+    asm::cli();
+    {
+        let mut task_control = TASK_CONTROL.lock();
+        if task_control.tasks.get(id).map(|task| task.flag) == Some(TaskState::Allocated) {
+            let _ = task_control.run(id, None, 0);
+        }
+    }
+    rflags::write(rf);
+}
+
+/// The index of the task running now.
+pub fn current() -> usize {
+    let rf = rflags::read();
+    asm::cli();
+    let id = TASK_CONTROL.lock().current();
+    rflags::write(rf);
+    id
+}
+
+/// Put the task to sleep until it is woken up by `wake` or `run`.
+/// If the task is the caller itself, this function returns after the task is woken up.
+pub fn sleep(id: usize) {
+    let rf = rflags::read();
+    asm::cli();
+    let mut task_control = TASK_CONTROL.lock();
+    if task_control.tasks[id].flag == TaskState::Running {
+        task_control.remove(id);
+        task_control.lv_change = true;
+        if id == task_control.current() {
+            // the idle task is always running, so there is always a task to switch to
+            let next = task_control.next_task().unwrap();
+            let context = task_control.prepare_switch(next);
+            drop(task_control);
+            if let Some((old_rsp, new_rsp)) = context {
+                unsafe {
+                    switch_context(old_rsp, new_rsp);
+                }
+            }
+            rflags::write(rf);
+            return;
+        }
+    }
+    drop(task_control);
+    rflags::write(rf);
+}
+
+/// Give the task its own address space, created by `AddressSpace::new`. The address space of the
+/// task is switched on every task switch, and freed when the task exits or another address space
+/// is given.
+pub fn set_address_space(id: usize, address_space: AddressSpace) {
+    let rf = rflags::read();
+    asm::cli();
+    let old = {
+        let mut task_control = TASK_CONTROL.lock();
+        let old = task_control.tasks[id].address_space.replace(address_space);
+        if id == task_control.current() {
+            unsafe { address_space::switch_to(task_control.level_4_frame(id)) };
+        }
+        old
+    };
+    // the old one is not active anymore
+    drop(old);
+    rflags::write(rf);
+}
+
+/// Whether the task can be terminated by `exit`.
+/// The first task and the idle task can't, and neither can unused tasks, e.g. any task before
+/// `init` is called.
+pub fn can_exit(id: usize) -> bool {
+    let rf = rflags::read();
+    asm::cli();
+    let can_exit = {
+        let task_control = TASK_CONTROL.lock();
+        task_control.tasks[id].flag != TaskState::Unused
+            && id != task_control.main
+            && id != task_control.idle
+    };
+    rflags::write(rf);
+    can_exit
+}
+
+/// Terminate the task running now and switch to the next task. The task can be allocated again.
+/// The address space of the task is freed, but timers and FIFOs created by the task are not.
+pub fn exit() -> ! {
+    asm::cli();
+    let mut task_control = TASK_CONTROL.lock();
+    let id = task_control.current();
+    assert!(
+        id != task_control.main && id != task_control.idle,
+        "the first task and the idle task can't exit"
+    );
+    if task_control.tasks[id].flag == TaskState::Running {
+        task_control.remove(id);
+    }
+    // the stack of this task is not touched until it is allocated again, which happens only
+    // after switching to another task
+    task_control.tasks[id].flag = TaskState::Unused;
+    task_control.lv_change = true;
+    // the idle task is always running, so there is always a task to switch to
+    let next = task_control.next_task().unwrap();
+    let address_space = task_control.tasks[id].address_space.take();
+    let (old_rsp, new_rsp) = task_control.prepare_switch(next).unwrap();
+    drop(task_control);
+    // the address space of the next task is active now, so that of this task can be freed
+    drop(address_space);
+    unsafe {
+        switch_context(old_rsp, new_rsp);
+    }
+    unreachable!("exited task was resumed");
+}
+
+/// The task whose kernel stack has the guard page at `address`, and the address of its entry
+/// function. An access to the guard page means the task overflowed its stack.
+/// Returns None if `address` is not in any guard page, or if the tasks are locked, e.g. the
+/// overflow happened while switching tasks.
+/// This function is only to be called by the exception handler.
+pub fn stack_overflow_task(address: x86_64::VirtAddr) -> Option<(usize, u64)> {
+    let task_control = TASK_CONTROL.try_lock()?;
+    task_control
+        .tasks
+        .iter()
+        .enumerate()
+        .find_map(|(id, task)| {
+            let stack = task.stack.as_ref()?;
+            if task.flag != TaskState::Unused && stack.is_guard(address) {
+                Some((id, task.entry))
+            } else {
+                None
+            }
+        })
+}
+
+/// Charge one tick to the task running now.
+/// This function is only to be called by `timer_interrupt_handler`. Therefore we don't need to
+/// care about interrupts.
+pub fn account_tick() {
+    TASK_CONTROL.lock().account_tick();
+}
+
+/// CPU load (%) over the last second, i.e. the ratio of ticks not spent by the idle task.
+pub fn cpu_load() -> u32 {
+    let rf = rflags::read();
+    asm::cli();
+    let load = TASK_CONTROL.lock().load;
+    rflags::write(rf);
+    load
+}
+
+/// Returns the number of ticks since `init` and the number of ticks spent by the idle task.
+pub fn idle_ticks() -> (u64, u64) {
+    let rf = rflags::read();
+    asm::cli();
+    let ticks = {
+        let task_control = TASK_CONTROL.lock();
+        (task_control.total_ticks, task_control.idle_ticks)
+    };
+    rflags::write(rf);
+    ticks
+}
+
+/// The number of ticks during which the task owned the CPU.
+pub fn task_ticks(id: usize) -> u64 {
+    let rf = rflags::read();
+    asm::cli();
+    let ticks = TASK_CONTROL.lock().tasks[id].ticks;
+    rflags::write(rf);
+    ticks
+}
+
+/// Switch to the next task.
+/// Tasks in the highest level which has running tasks are executed in round-robin order, and each
+/// of them runs for `priority` ticks.
+/// This function is only to be called by `timer_interrupt_handler` after the task switch timer
+/// timed out. Therefore we don't need to care about interrupts.
+pub fn switch() {
+    let context = {
+        let mut task_control = TASK_CONTROL.lock();
+        let current = task_control.current();
+        let now_lv = task_control.now_lv;
+        let level = &mut task_control.levels[now_lv];
+        // the current task may have been removed by `sleep`, and then `now` already points to the
+        // next one
+        if !level.tasks_running.is_empty() && level.current() == current {
+            level.now = (level.now + 1) % level.tasks_running.len();
+        }
+        let next = task_control.next_task();
+        let priority = next.map_or(DEFAULT_PRIORITY, |id| task_control.tasks[id].priority);
+        TIMER_CONTROL
+            .lock()
+            .set_time(task_control.timer_id, priority);
+        next.and_then(|id| task_control.prepare_switch(id))
+    };
+    if let Some((old_rsp, new_rsp)) = context {
+        unsafe {
+            switch_context(old_rsp, new_rsp);
+        }
+    }
+}
+
+#[test_case]
+fn test_run_validation() {
+    let mut task_control = TaskControl::new();
+    assert_eq!(task_control.run(0, Some(0), 0), Err(RunError::InvalidTask));
+    assert_eq!(
+        task_control.run(MAX_TASKS, Some(0), 0),
+        Err(RunError::InvalidTask)
+    );
+    let id = task_control.find_unused().unwrap();
+    assert_eq!(
+        task_control.run(id, Some(MAX_TASKLEVELS), 0),
+        Err(RunError::InvalidLevel)
+    );
+    assert_eq!(task_control.tasks[id].flag, TaskState::Allocated);
+    assert_eq!(task_control.run(id, Some(MAX_TASKLEVELS - 1), 0), Ok(()));
+    // None keeps the level
+    assert_eq!(task_control.run(id, None, 3), Ok(()));
+    assert_eq!(task_control.tasks[id].level, MAX_TASKLEVELS - 1);
+    assert_eq!(task_control.tasks[id].priority, 3);
+}
+
+#[test_case]
+fn test_run_level_full() {
+    let mut task_control = TaskControl::new();
+    for _ in 0..MAX_TASKS_LV {
+        let id = task_control.find_unused().unwrap();
+        task_control.run(id, Some(1), 0).unwrap();
+    }
+    let id = task_control.find_unused().unwrap();
+    assert_eq!(task_control.run(id, Some(1), 0), Err(RunError::LevelFull));
+    assert_eq!(task_control.tasks[id].flag, TaskState::Allocated);
+
+    // a task failing to move to the full level keeps running in its level
+    task_control.run(id, Some(2), 3).unwrap();
+    assert_eq!(task_control.run(id, Some(1), 5), Err(RunError::LevelFull));
+    assert_eq!(task_control.tasks[id].level, 2);
+    assert_eq!(task_control.tasks[id].priority, 3);
+    assert_eq!(task_control.levels[2].tasks_running, [id]);
+    // the tasks already in the full level can still change their priority
+    let first = task_control.levels[1].tasks_running[0];
+    assert_eq!(task_control.run(first, Some(1), 4), Ok(()));
+}
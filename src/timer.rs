@@ -1,114 +1,233 @@
 use crate::asm;
-use crate::fifo::{FIFO, GLOBAL_FIFO_BUF};
+use crate::fifo::{Event, FIFO, GLOBAL_FIFO_BUF};
+use crate::util::clip;
 use alloc::{vec, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
 use spin::Mutex;
 use x86_64::instructions::port;
 use x86_64::registers::rflags;
 
-const MAX_TIMER: usize = 100;
+const MAX_TIMER: usize = 500;
+/// The index of the sentinel timer, which never times out and always terminates the list of
+/// running timers.
+const SENTINEL: usize = MAX_TIMER;
+
+/// Identifies the timer which timed out in `Event::Timer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(pub usize);
+
+/// Wraparound-safe comparison of tick counts: true if `a` is earlier than `b`.
+/// This is correct as long as `a` and `b` are less than 2^63 ticks apart.
+pub fn tick_before(a: u64, b: u64) -> bool {
+    (a.wrapping_sub(b) as i64) < 0
+}
 
 pub struct TIMERCTL {
-    /// global count of this OS. increases every 0.01s
-    pub count: u32,
-    /// next timing of timeout
-    pub next: u32,
-    pub timers: Vec<TIMER>,
-    /// The index of timers used (`TimerState::Using`) now.
-    /// Sorted by `next` in ascending order.
-    pub used_timers: Vec<usize>,
-    fifo: &'static Mutex<FIFO<u32>>,
+    /// global count of this OS. increases every tick (see `frequency`) and wraps around on overflow
+    pub count: u64,
+    /// next timing of timeout. Valid only while some timer is used.
+    pub next: u64,
+    /// `MAX_TIMER` timers and the sentinel.
+    timers: Vec<TIMER>,
+    /// The index of the first timer used (`TimerState::Using`) now.
+    /// Used timers are linked by `TIMER::next_timer` in ascending order of `timeout`, and the list
+    /// always ends with `SENTINEL`. So arming and firing timers never allocate memory.
+    head: usize,
+    /// The index of the timer used for task switch. This timer does not push to any FIFO.
+    pub task_timer: Option<usize>,
 }
 
 use lazy_static::lazy_static;
 lazy_static! {
-    pub static ref TIMER_CONTROL: Mutex<TIMERCTL> = Mutex::new(TIMERCTL {
-        count: 0,
-        next: core::u32::MAX,
-        timers: vec![TIMER::new(0); MAX_TIMER],
-        used_timers: vec![],
-        fifo: &GLOBAL_FIFO_BUF,
-    });
+    pub static ref TIMER_CONTROL: Mutex<TIMERCTL> = Mutex::new(TIMERCTL::new());
 }
 
 impl TIMERCTL {
+    pub fn new() -> Self {
+        let mut timers = vec![TIMER::new(0); MAX_TIMER + 1];
+        timers[SENTINEL].flag = TimerState::Using;
+        Self {
+            count: 0,
+            next: 0,
+            timers,
+            head: SENTINEL,
+            task_timer: None,
+        }
+    }
     pub fn allocate(&mut self) -> Option<usize> {
         for i in 0..MAX_TIMER {
             if self.timers[i].flag == TimerState::Unused {
-                self.timers[i].flag = TimerState::Using;
+                self.timers[i].flag = TimerState::Allocated;
                 return Some(i);
             }
         }
         return None;
     }
+    /// Cancel the timer and make it available for `allocate` again.
     pub fn deallocate(&mut self, id: usize) {
-        self.timers[id].flag = TimerState::Unused;
+        self.cancel(id);
+        self.timers[id] = TIMER::new(0);
     }
+    /// Set the FIFO which receives `Event::Timer(TimerId(id), data)` when the timer times out.
+    pub fn init(&mut self, id: usize, fifo: &'static Mutex<FIFO<Event>>, data: u32) {
+        self.timers[id].fifo = fifo;
+        self.timers[id].data = data;
+    }
+    pub fn set_data(&mut self, id: usize, data: u32) {
+        self.timers[id].data = data;
+    }
+    /// Start the timer, which times out once after `wait_time` ticks.
+    /// If the timer is already running, it is restarted.
     pub fn set_time(&mut self, id: usize, wait_time: u32) {
-        let timeout = self.count + wait_time;
+        self.timers[id].interval = None;
+        self.arm(id, wait_time);
+    }
+    /// Start the timer, which times out every `interval` ticks until it is canceled.
+    /// If the timer is already running, it is restarted.
+    pub fn set_periodic(&mut self, id: usize, interval: u32) {
+        // a zero interval would make the timer fire forever in one tick
+        let interval = core::cmp::max(interval, 1);
+        self.timers[id].interval = Some(interval);
+        self.arm(id, interval);
+    }
+    /// Stop the timer if it is running. The timer is still allocated.
+    pub fn cancel(&mut self, id: usize) {
+        let rf = rflags::read();
+        asm::cli();
+        if self.timers[id].flag == TimerState::Using {
+            self.remove(id);
+            self.timers[id].flag = TimerState::Allocated;
+            self.update_next();
+        }
+        rflags::write(rf);
+    }
+    fn arm(&mut self, id: usize, wait_time: u32) {
+        let timeout = self.count.wrapping_add(wait_time as u64);
         let rf = rflags::read();
         asm::cli();
         {
-            self.timers[id].timeout = timeout;
-            // どこに入れればいいかを探す
-            let index_to_push = self
-                .used_timers
-                .iter()
-                .position(|&timer_idx| self.timers[timer_idx].timeout > timeout)
-                .unwrap_or(self.used_timers.len());
-            // 入れる
-            self.used_timers.insert(index_to_push, id);
-
-            self.next = core::cmp::min(self.next, timeout);
-            self.timers[id].flag = TimerState::Using;
+            if self.timers[id].flag == TimerState::Using {
+                self.remove(id);
+            }
+            self.insert(id, timeout);
+            self.update_next();
         }
         rflags::write(rf);
     }
+    /// Insert the timer into the list of used timers keeping it sorted.
+    /// Timers with the same timeout fire in the order of insertion.
+    fn insert(&mut self, id: usize, timeout: u64) {
+        self.timers[id].timeout = timeout;
+        self.timers[id].flag = TimerState::Using;
+        // どこに入れればいいかを探す
+        if self.is_before(timeout, self.head) {
+            // 先頭に入れる
+            self.timers[id].next_timer = self.head;
+            self.head = id;
+            return;
+        }
+        let mut prev = self.head;
+        loop {
+            let next = self.timers[prev].next_timer;
+            if self.is_before(timeout, next) {
+                // prevとnextの間に入れる
+                self.timers[prev].next_timer = id;
+                self.timers[id].next_timer = next;
+                return;
+            }
+            prev = next;
+        }
+    }
+    /// Whether a timer timing out at `timeout` should fire before the timer `id`.
+    fn is_before(&self, timeout: u64, id: usize) -> bool {
+        id == SENTINEL || tick_before(timeout, self.timers[id].timeout)
+    }
+    /// Unlink the timer from the list of used timers.
+    fn remove(&mut self, id: usize) {
+        if self.head == id {
+            self.head = self.timers[id].next_timer;
+            return;
+        }
+        let mut prev = self.head;
+        while prev != SENTINEL {
+            let next = self.timers[prev].next_timer;
+            if next == id {
+                self.timers[prev].next_timer = self.timers[id].next_timer;
+                return;
+            }
+            prev = next;
+        }
+    }
+    fn update_next(&mut self) {
+        self.next = self.timers[self.head].timeout;
+    }
+    /// Iterate the index of used timers in the order of timeout.
+    pub fn queued(&self) -> impl Iterator<Item = usize> + '_ {
+        let mut id = self.head;
+        core::iter::from_fn(move || {
+            if id == SENTINEL {
+                None
+            } else {
+                let current = id;
+                id = self.timers[id].next_timer;
+                Some(current)
+            }
+        })
+    }
     /// This function is only to be called by `interrupt.rs`. Therefore we don't need to care about
     /// interrupts
-    pub fn shift_timers(&mut self) {
-        if self.count >= self.next {
-            let mut num_of_timeouts = 0;
-            // iterate for timers in use
-            for timer_id in self.used_timers.clone().iter() {
-                if self.timers[*timer_id].timeout > self.count {
-                    break;
-                }
+    ///
+    /// Returns true if the task switch timer timed out.
+    pub fn shift_timers(&mut self) -> bool {
+        let mut task_switch = false;
+        if self.head != SENTINEL && !tick_before(self.count, self.next) {
+            while self.head != SENTINEL && !tick_before(self.count, self.timers[self.head].timeout)
+            {
                 // timeout happened for this timer
-                num_of_timeouts += 1;
-                self.push_timeout_signal(*timer_id);
-                self.timers[*timer_id].flag = TimerState::Allocated;
-            }
-            // `num_of_timeouts` timers timed out
-            self.used_timers = self.used_timers.split_off(num_of_timeouts);
-            if self.used_timers.len() > 0 {
-                self.next = self.timers[self.used_timers[0]].timeout;
-            } else {
-                self.next = core::u32::MAX;
+                let timer_id = self.head;
+                self.head = self.timers[timer_id].next_timer;
+                self.timers[timer_id].flag = TimerState::Allocated;
+                if Some(timer_id) == self.task_timer {
+                    task_switch = true;
+                } else {
+                    self.push_timeout_signal(timer_id);
+                }
+                // periodic timers are re-armed relative to the last timeout to avoid drift
+                if let Some(interval) = self.timers[timer_id].interval {
+                    let timeout = self.timers[timer_id].timeout.wrapping_add(interval as u64);
+                    self.insert(timer_id, timeout);
+                }
             }
+            self.update_next();
         }
+        task_switch
     }
     pub fn push_timeout_signal(&mut self, id: usize) {
         let data = self.timers[id].data;
 
         let rf = rflags::read();
         asm::cli();
-        self.fifo.lock().push(data).unwrap();
-        rflags::write(rf);
-    }
-    pub fn pop(&mut self) -> Result<u32, ()> {
-        let rf = rflags::read();
-        asm::cli();
-        let result = self.fifo.lock().pop();
+        // overflow is recorded in the statistics of the FIFO
+        let _ = self.timers[id]
+            .fifo
+            .lock()
+            .push(Event::Timer(TimerId(id), data));
         rflags::write(rf);
-        result
     }
 }
 
 #[derive(Clone)]
 pub struct TIMER {
-    pub timeout: u32,
+    pub timeout: u64,
     pub flag: TimerState,
     pub data: u32,
+    /// FIFO which receives `Event::Timer` when this timer times out.
+    pub fifo: &'static Mutex<FIFO<Event>>,
+    /// Interval of periodic timers. None for one-shot timers.
+    pub interval: Option<u32>,
+    /// The index of the timer which times out next to this one. Valid only while `Using`.
+    next_timer: usize,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -124,19 +243,299 @@ impl TIMER {
             timeout: 0,
             flag: TimerState::Unused,
             data,
+            fifo: &GLOBAL_FIFO_BUF,
+            interval: None,
+            next_timer: SENTINEL,
         }
     }
 }
 
+/// Handle of a timer allocated from `TIMER_CONTROL`.
+/// The timer is canceled and deallocated when the handle is dropped.
+pub struct Timer {
+    id: usize,
+}
+
+impl Timer {
+    /// Allocate a timer which pushes `Event::Timer(self.id(), data)` to `fifo` when it times out.
+    /// Returns None if all timers are in use.
+    pub fn new(fifo: &'static Mutex<FIFO<Event>>, data: u32) -> Option<Self> {
+        Self::with_timer_control(|timer_control| {
+            let id = timer_control.allocate()?;
+            timer_control.init(id, fifo, data);
+            Some(Self { id })
+        })
+    }
+    /// The id in `Event::Timer` pushed by this timer.
+    pub fn id(&self) -> TimerId {
+        TimerId(self.id)
+    }
+    /// Time out once after `delay` ticks.
+    pub fn set_oneshot(&self, delay: u32) {
+        Self::with_timer_control(|timer_control| timer_control.set_time(self.id, delay));
+    }
+    /// Time out every `interval` ticks until canceled.
+    pub fn set_periodic(&self, interval: u32) {
+        Self::with_timer_control(|timer_control| timer_control.set_periodic(self.id, interval));
+    }
+    /// Time out once after `delay`, rounded up to ticks.
+    pub fn set_oneshot_duration(&self, delay: Duration) {
+        self.set_oneshot(ticks_for_timer(delay));
+    }
+    /// Time out every `interval`, rounded up to ticks, until canceled.
+    pub fn set_periodic_duration(&self, interval: Duration) {
+        self.set_periodic(ticks_for_timer(interval));
+    }
+    /// Change the data pushed at the following timeouts.
+    pub fn set_data(&self, data: u32) {
+        Self::with_timer_control(|timer_control| timer_control.set_data(self.id, data));
+    }
+    /// Stop the timer. It can be started again with `set_oneshot` or `set_periodic`.
+    pub fn cancel(&self) {
+        Self::with_timer_control(|timer_control| timer_control.cancel(self.id));
+    }
+    /// `TIMER_CONTROL` is locked in the timer interrupt, so it must be locked after `cli`.
+    fn with_timer_control<R>(f: impl FnOnce(&mut TIMERCTL) -> R) -> R {
+        let rf = rflags::read();
+        asm::cli();
+        let result = f(&mut TIMER_CONTROL.lock());
+        rflags::write(rf);
+        result
+    }
+}
+
+/// Ticks of `duration` as a wait time of timers, saturating at `u32::MAX` ticks.
+fn ticks_for_timer(duration: Duration) -> u32 {
+    core::cmp::min(duration_to_ticks(duration), core::u32::MAX as u64) as u32
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let id = self.id;
+        Self::with_timer_control(|timer_control| timer_control.deallocate(id));
+    }
+}
+
 const PIT_CTRL: u16 = 0x0043;
 const PIT_CNT0: u16 = 0x0040;
 
-pub fn init_pit() {
+/// Frequency of the clock driving the counters of PIT (Hz).
+pub const PIT_CLOCK_HZ: u32 = 1_193_182;
+/// Frequency of the timer interrupt set by `init` (Hz).
+pub const DEFAULT_TIMER_HZ: u32 = 100;
+
+/// Frequency of the timer interrupt, i.e. the number of ticks per second.
+static TIMER_HZ: AtomicU32 = AtomicU32::new(DEFAULT_TIMER_HZ);
+/// The reload value of the counter 0 of PIT. 0x10000 is written as 0.
+static PIT_DIVISOR: AtomicU32 = AtomicU32::new(PIT_CLOCK_HZ / DEFAULT_TIMER_HZ);
+
+/// Make PIT raise the timer interrupt `hz` times per second.
+/// The frequency is rounded to the nearest one PIT can generate, which `frequency` returns.
+pub fn init_pit(hz: u32) {
+    assert!(hz > 0, "frequency of the timer interrupt must not be 0");
+    let divisor = divisor_for(hz);
+    PIT_DIVISOR.store(divisor, Ordering::Relaxed);
+    TIMER_HZ.store((PIT_CLOCK_HZ + divisor / 2) / divisor, Ordering::Relaxed);
+
     let mut port_control = port::PortWriteOnly::new(PIT_CTRL);
     let mut port_counter = port::PortWriteOnly::new(PIT_CNT0);
     unsafe {
+        // counter 0, low byte then high byte, mode 2 (rate generator)
         port_control.write(0x34u8);
-        port_counter.write(0x9cu8);
-        port_counter.write(0x2eu8);
+        port_counter.write((divisor & 0xff) as u8);
+        port_counter.write((divisor >> 8 & 0xff) as u8);
+    }
+}
+
+/// The reload value making PIT count `hz` times per second, rounded to the nearest valid one.
+fn divisor_for(hz: u32) -> u32 {
+    // the counter is 16 bit, and writing 0 means 0x10000. 1 is invalid in mode 2.
+    clip((PIT_CLOCK_HZ + hz / 2) / hz, 2, 0x10000)
+}
+
+/// The number of ticks per second.
+pub fn frequency() -> u32 {
+    TIMER_HZ.load(Ordering::Relaxed)
+}
+
+/// Convert `duration` to ticks, rounding up so that waits are never shorter than `duration`.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * frequency() as u128 + 999_999_999) / 1_000_000_000;
+    core::cmp::min(ticks, core::u64::MAX as u128) as u64
+}
+
+/// Convert `ticks` to the time they take.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * 1_000_000_000 / frequency() as u128;
+    Duration::new(
+        (nanos / 1_000_000_000) as u64,
+        (nanos % 1_000_000_000) as u32,
+    )
+}
+
+/// Time elapsed since the timer interrupt started.
+pub fn uptime() -> Duration {
+    let rf = rflags::read();
+    asm::cli();
+    let count = TIMER_CONTROL.lock().count;
+    rflags::write(rf);
+    ticks_to_duration(count)
+}
+
+/// Wait for `us` microseconds by polling the counter 0 of PIT.
+/// The counter runs at `PIT_CLOCK_HZ` regardless of the CPU speed and works while interrupts are
+/// disabled, so drivers can use this for short waits. Don't use this for long waits: it occupies
+/// the CPU, so use `Timer` instead.
+pub fn delay_us(us: u64) {
+    let divisor = pit_divisor();
+    let mut remaining = (us as u128 * PIT_CLOCK_HZ as u128 / 1_000_000) as u64;
+    let mut prev = read_pit_counter();
+    while remaining > 0 {
+        core::hint::spin_loop();
+        let now = read_pit_counter();
+        // the counter counts down from `divisor` to 1 and then is reloaded
+        let elapsed = if now <= prev {
+            prev - now
+        } else {
+            prev + divisor - now
+        };
+        remaining = remaining.saturating_sub(elapsed);
+        prev = now;
+    }
+}
+
+/// The reload value of the counter 0 of PIT, i.e. PIT clocks in one period.
+pub(crate) fn pit_divisor() -> u64 {
+    PIT_DIVISOR.load(Ordering::Relaxed) as u64
+}
+
+/// Read the current value of the counter 0 of PIT.
+pub(crate) fn read_pit_counter() -> u64 {
+    let mut port_control = port::PortWriteOnly::new(PIT_CTRL);
+    let mut port_counter = port::Port::<u8>::new(PIT_CNT0);
+    let rf = rflags::read();
+    asm::cli();
+    let count = unsafe {
+        // latch the counter 0 so that both bytes are of the same value
+        port_control.write(0x00u8);
+        let low = port_counter.read() as u64;
+        let high = port_counter.read() as u64;
+        high << 8 | low
+    };
+    rflags::write(rf);
+    // 0 is read right after the reload of 0x10000
+    if count == 0 {
+        0x10000
+    } else {
+        count
+    }
+}
+
+#[test_case]
+fn test_timer_cancel_and_drop() {
+    use alloc::boxed::Box;
+    let fifo: &'static Mutex<FIFO<Event>> = Box::leak(Box::new(Mutex::new(FIFO::new(4))));
+    let timer = Timer::new(fifo, 0).unwrap();
+    let id = timer.id().0;
+    let queued_count = |id| {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            TIMER_CONTROL.lock().queued().filter(|&i| i == id).count()
+        })
+    };
+
+    timer.set_periodic(1000);
+    // re-arming doesn't queue the timer twice
+    timer.set_oneshot(1000);
+    assert_eq!(queued_count(id), 1);
+    timer.cancel();
+    assert_eq!(queued_count(id), 0);
+
+    timer.set_oneshot(1000);
+    drop(timer);
+    assert_eq!(queued_count(id), 0);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        assert!(TIMER_CONTROL.lock().timers[id].flag == TimerState::Unused);
+    });
+}
+
+#[test_case]
+fn test_timer_order() {
+    use alloc::boxed::Box;
+    let fifo: &'static Mutex<FIFO<Event>> = Box::leak(Box::new(Mutex::new(FIFO::new(8))));
+    let mut timer_control = TIMERCTL::new();
+    let waits = [30, 10, 20, 10, 40];
+    for (data, &wait) in waits.iter().enumerate() {
+        let id = timer_control.allocate().unwrap();
+        timer_control.init(id, fifo, data as u32);
+        timer_control.set_time(id, wait);
     }
+    for _ in 0..40 {
+        timer_control.count += 1;
+        timer_control.shift_timers();
+    }
+    let mut fired = alloc::vec::Vec::new();
+    while let Ok(Event::Timer(_, data)) = fifo.lock().pop() {
+        fired.push(data);
+    }
+    // timers with the same timeout fire in the order of `set_time`
+    assert_eq!(fired, [1, 3, 2, 0, 4]);
+    assert_eq!(timer_control.queued().count(), 0);
+}
+
+#[test_case]
+fn test_timer_counter_wraparound() {
+    use alloc::boxed::Box;
+    let fifo: &'static Mutex<FIFO<Event>> = Box::leak(Box::new(Mutex::new(FIFO::new(8))));
+    let mut timer_control = TIMERCTL::new();
+    let start = core::u64::MAX - 5;
+    timer_control.count = start;
+    // timeouts of all but the second timer are beyond the overflow
+    let waits = [10, 3, 20, 6];
+    for (data, &wait) in waits.iter().enumerate() {
+        let id = timer_control.allocate().unwrap();
+        timer_control.init(id, fifo, data as u32);
+        timer_control.set_time(id, wait);
+    }
+    let mut fired = alloc::vec::Vec::new();
+    for tick in 1..=25 {
+        timer_control.count = timer_control.count.wrapping_add(1);
+        timer_control.shift_timers();
+        while let Ok(Event::Timer(_, data)) = fifo.lock().pop() {
+            fired.push((data, tick));
+        }
+    }
+    // every timer fires exactly after its wait time, in order
+    assert_eq!(fired, [(1, 3), (3, 6), (0, 10), (2, 20)]);
+}
+
+#[test_case]
+fn test_divisor_for() {
+    assert_eq!(divisor_for(DEFAULT_TIMER_HZ), 11932);
+    // too low frequencies are clamped to the largest divisor
+    assert_eq!(divisor_for(1), 0x10000);
+    // too high frequencies are clamped to the smallest divisor valid in mode 2
+    assert_eq!(divisor_for(PIT_CLOCK_HZ), 2);
+    assert_eq!(divisor_for(PIT_CLOCK_HZ * 2), 2);
+    assert_eq!(divisor_for(core::u32::MAX), 2);
+}
+
+#[test_case]
+fn test_duration_to_ticks() {
+    assert_eq!(frequency(), DEFAULT_TIMER_HZ);
+    assert_eq!(duration_to_ticks(Duration::from_millis(0)), 0);
+    assert_eq!(duration_to_ticks(Duration::from_millis(10)), 1);
+    // waits are rounded up
+    assert_eq!(duration_to_ticks(Duration::from_micros(10_001)), 2);
+    assert_eq!(duration_to_ticks(Duration::from_secs(3)), 300);
+    assert_eq!(ticks_to_duration(150), Duration::from_millis(1500));
+}
+
+#[test_case]
+fn test_delay_us() {
+    let count =
+        || x86_64::instructions::interrupts::without_interrupts(|| TIMER_CONTROL.lock().count);
+    let start = count();
+    // 3 ticks at 100Hz
+    delay_us(30_000);
+    assert!(count().wrapping_sub(start) >= 2);
 }
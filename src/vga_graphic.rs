@@ -1,9 +1,10 @@
+use crate::rtc::DateTime;
+use crate::sync::IrqMutex;
 use crate::util::clip;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use vga::drawing::Point;
 use vga::writers::{Graphics320x200x256, GraphicsWriter};
 
@@ -43,8 +44,9 @@ lazy_static! {
         mode.clear_screen(Color::Black as u8);
         mode
     };
-    pub static ref SHEET_CONTROL: Mutex<SheetControl<'static>> =
-        Mutex::new(SheetControl::new(&MODE));
+    /// Shared by tasks on any level, so this is an `IrqMutex`.
+    pub static ref SHEET_CONTROL: IrqMutex<SheetControl<'static>> =
+        IrqMutex::new(SheetControl::new(&MODE));
     pub static ref MOUSE_ID: usize = {
         let mut sheet_control = SHEET_CONTROL.lock();
         let mouse_id = sheet_control
@@ -442,6 +444,29 @@ impl Sheet {
         );
         self.boxfill(White, ((xsize - 47, ysize - 3), (xsize - 4, ysize - 3)));
         self.boxfill(White, ((xsize - 3, ysize - 24), (xsize - 3, ysize - 3)));
+
+        self.draw_clock(crate::rtc::read());
+    }
+    /// Draw `time` as "HH:MM" in the clock at the right end of the taskbar of the background.
+    pub fn draw_clock(&mut self, time: DateTime) {
+        let (xsize, ysize) = self.size;
+        let area = ((xsize - 46, ysize - 23), (xsize - 4, ysize - 4));
+        self.boxfill(Color::LightGrey, area);
+        let text = [
+            b'0' + time.hour / 10,
+            b'0' + time.hour % 10,
+            b':',
+            b'0' + time.minute / 10,
+            b'0' + time.minute % 10,
+        ];
+        for (i, &c) in text.iter().enumerate() {
+            self.draw_character(
+                (xsize - 45 + FONT_WIDTH * i as isize, ysize - 21),
+                c as char,
+                Color::Black,
+            );
+        }
+        self.areas_to_refresh.push(area);
     }
     /// Set up this sheet as an ordinary sheetby painting it with LightGrey, draw CLOSE_BUTTON, etc.
     pub fn make_sheet(&mut self, title: &str) {
@@ -0,0 +1,98 @@
+#!/usr/bin/env python3
+# Writes the symbol table for backtraces into the `.kernel_symbols` section of the kernel ELF.
+#
+# usage: process_symbols.py [path to the kernel ELF]
+#        process_symbols.py --run [path to the kernel ELF] [arguments of bootimage runner...]
+#
+# The section holds the placeholder generated by build.rs, which has the size of SYMBOL_TABLE_SIZE.
+# The table is written over it in place, so no function moves and the table stays correct.
+# With --run, `bootimage runner` is run with the ELF afterwards, which is what cargo does as the
+# runner configured in .cargo/config.toml.
+import os
+import re
+import struct
+import subprocess
+import sys
+
+SYMBOL_TABLE_SIZE = 0x80000
+SECTION_NAME = b".kernel_symbols"
+HASH = re.compile(r"::h[0-9a-f]{16}$")
+
+
+def read_symbols(elf):
+    nm = os.environ.get("NM", "nm")
+    output = subprocess.run(
+        [nm, "--defined-only", "--demangle", elf],
+        check=True, capture_output=True, text=True,
+    ).stdout
+    symbols = {}
+    for line in output.splitlines():
+        fields = line.split(" ", 2)
+        if len(fields) != 3 or fields[1] not in "TtWw":
+            continue
+        address = int(fields[0], 16)
+        symbols.setdefault(address, HASH.sub("", fields[2]))
+    return sorted(symbols.items())
+
+
+def make_table(symbols):
+    # header: magic and the number of symbols
+    # entries: address (u64), offset of the name from the start of the table (u32), length (u32)
+    # followed by the names
+    header_size = 8
+    entry_size = 16
+    names = b""
+    entries = b""
+    names_start = header_size + entry_size * len(symbols)
+    for address, name in symbols:
+        name = name.encode()
+        entries += struct.pack("<QII", address, names_start + len(names), len(name))
+        names += name
+    table = b"KSYM" + struct.pack("<I", len(symbols)) + entries + names
+    if len(table) > SYMBOL_TABLE_SIZE:
+        sys.exit("symbol table is too large: {} bytes".format(len(table)))
+    return table + b"\0" * (SYMBOL_TABLE_SIZE - len(table))
+
+
+def find_section(image, name):
+    """Returns the file offset and the size of the section `name` of the ELF64 `image`."""
+    if image[:4] != b"\x7fELF" or image[4] != 2:
+        sys.exit("not an ELF64 file")
+    (shoff,) = struct.unpack_from("<Q", image, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", image, 0x3a)
+
+    def section(index):
+        header = shoff + index * shentsize
+        (name_offset,) = struct.unpack_from("<I", image, header)
+        offset, size = struct.unpack_from("<QQ", image, header + 0x18)
+        return name_offset, offset, size
+
+    _, names, _ = section(shstrndx)
+    for index in range(shnum):
+        name_offset, offset, size = section(index)
+        start = names + name_offset
+        if image[start:image.index(b"\0", start)] == name:
+            return offset, size
+    sys.exit("no {} section in the kernel".format(name.decode()))
+
+
+def patch(elf):
+    table = make_table(read_symbols(elf))
+    with open(elf, "r+b") as f:
+        image = f.read()
+        offset, size = find_section(image, SECTION_NAME)
+        if size != SYMBOL_TABLE_SIZE:
+            sys.exit("{} has {} bytes instead of {}".format(
+                SECTION_NAME.decode(), size, SYMBOL_TABLE_SIZE))
+        f.seek(offset)
+        f.write(table)
+
+
+if __name__ == '__main__':
+    run = len(sys.argv) > 1 and sys.argv[1] == "--run"
+    args = sys.argv[2:] if run else sys.argv[1:]
+    if not args:
+        sys.exit("usage: process_symbols.py [--run] ELF [ARGS...]")
+    patch(args[0])
+    if run:
+        os.execvp("bootimage", ["bootimage", "runner"] + args)
@@ -0,0 +1,25 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+
+use haribote::fifo::FIFO;
+use haribote::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("fifo_zero_capacity::new_panics...\t");
+    // a zero capacity FIFO would panic later in `push` with `OverflowPolicy::DropOldest`, possibly
+    // in an interrupt handler, so it is rejected when it is created
+    let _fifo: FIFO<u32> = FIFO::new(0);
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
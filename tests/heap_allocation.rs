@@ -18,7 +18,7 @@ pub fn main(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
+        unsafe { memory::BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
     test_main();
     loop {}
@@ -48,6 +48,8 @@ fn large_vec() {
     assert_eq!(vec.iter().sum::<u64>(), n * (n - 1) / 2)
 }
 
+// the bump allocator never reuses memory while the long-living item is alive
+#[cfg(not(feature = "bump_allocator"))]
 #[test_case]
 fn many_boxes() {
     use haribote::allocator::HEAP_SIZE;
@@ -58,3 +60,118 @@ fn many_boxes() {
     }
     assert_eq!(*long_living_item, 42);
 }
+
+// The same allocation patterns run against every allocator, each with its own heap. The global
+// allocator above is one of them selected by the cargo features.
+
+use alloc::alloc::{GlobalAlloc, Layout};
+use haribote::allocator::{
+    buddy::BuddyAllocator, bump::BumpAllocator, fixed_size_block::FixedSizeBlockAllocator, Locked,
+};
+
+const TEST_HEAP_SIZE: usize = 64 * 1024;
+
+#[repr(align(65536))]
+struct TestHeap([u8; TEST_HEAP_SIZE]);
+
+/// Allocations of various sizes and alignments are usable at the same time.
+fn sizes_and_alignments(allocator: &impl GlobalAlloc) {
+    let layouts = [
+        (1, 1),
+        (8, 8),
+        (24, 8),
+        (100, 4),
+        (512, 64),
+        (3000, 8),
+        (4096, 4096),
+    ];
+    let mut ptrs = [core::ptr::null_mut(); 7];
+    for (i, &(size, align)) in layouts.iter().enumerate() {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        ptrs[i] = unsafe { allocator.alloc(layout) };
+        assert!(!ptrs[i].is_null());
+        assert_eq!(ptrs[i] as usize % align, 0);
+        unsafe { core::ptr::write_bytes(ptrs[i], i as u8, size) };
+    }
+    // no allocation overlaps another one
+    for (i, &(size, align)) in layouts.iter().enumerate() {
+        let bytes = unsafe { core::slice::from_raw_parts(ptrs[i], size) };
+        assert!(bytes.iter().all(|&byte| byte == i as u8));
+        unsafe { allocator.dealloc(ptrs[i], Layout::from_size_align(size, align).unwrap()) };
+    }
+}
+
+/// Memory freed is reused, so that repeated allocations never exhaust the heap.
+fn repeated_allocations(allocator: &impl GlobalAlloc, long_living: bool) {
+    let layout = Layout::new::<u64>();
+    let long_living_item = if long_living {
+        let ptr = unsafe { allocator.alloc(layout) } as *mut u64;
+        unsafe { ptr.write(42) };
+        Some(ptr)
+    } else {
+        None
+    };
+    for i in 0..TEST_HEAP_SIZE {
+        let ptr = unsafe { allocator.alloc(layout) } as *mut u64;
+        assert!(!ptr.is_null());
+        unsafe {
+            ptr.write(i as u64);
+            assert_eq!(ptr.read(), i as u64);
+            allocator.dealloc(ptr as *mut u8, layout);
+        }
+    }
+    if let Some(ptr) = long_living_item {
+        unsafe {
+            assert_eq!(ptr.read(), 42);
+            allocator.dealloc(ptr as *mut u8, layout);
+        }
+    }
+}
+
+/// After the heap is filled with small allocations and they are freed, a large allocation
+/// succeeds.
+fn exhaustion_and_recovery(allocator: &impl GlobalAlloc) {
+    let small = Layout::from_size_align(1024, 8).unwrap();
+    let mut ptrs = [core::ptr::null_mut(); TEST_HEAP_SIZE / 1024];
+    let mut count = 0;
+    while count < ptrs.len() {
+        let ptr = unsafe { allocator.alloc(small) };
+        if ptr.is_null() {
+            break;
+        }
+        ptrs[count] = ptr;
+        count += 1;
+    }
+    assert!(count > 0);
+    for &ptr in &ptrs[..count] {
+        unsafe { allocator.dealloc(ptr, small) };
+    }
+    let large = Layout::from_size_align(TEST_HEAP_SIZE / 4, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(large) };
+    assert!(!ptr.is_null());
+    unsafe { allocator.dealloc(ptr, large) };
+}
+
+/// `$long_living` is whether `repeated_allocations` keeps an allocation alive, which the bump
+/// allocator can't afford since it reuses memory only when every allocation is freed.
+macro_rules! allocator_tests {
+    ($name:ident, $allocator:ty, $long_living:expr) => {
+        #[test_case]
+        fn $name() {
+            static mut HEAP: TestHeap = TestHeap([0; TEST_HEAP_SIZE]);
+            let allocator = Locked::new(<$allocator>::new());
+            unsafe {
+                allocator
+                    .lock()
+                    .init(HEAP.0.as_mut_ptr() as usize, TEST_HEAP_SIZE)
+            };
+            sizes_and_alignments(&allocator);
+            repeated_allocations(&allocator, $long_living);
+            exhaustion_and_recovery(&allocator);
+        }
+    };
+}
+
+allocator_tests!(bump_allocator, BumpAllocator, false);
+allocator_tests!(fixed_size_block_allocator, FixedSizeBlockAllocator, true);
+allocator_tests!(buddy_allocator, BuddyAllocator, true);
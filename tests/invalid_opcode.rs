@@ -0,0 +1,28 @@
+#![no_std]
+#![no_main]
+#![feature(asm)]
+#![feature(custom_test_frameworks)]
+
+use core::panic::PanicInfo;
+
+use haribote::serial_println;
+use haribote::{exit_qemu, QemuExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    haribote::gdt::init();
+    haribote::interrupts::init_idt();
+
+    // the handler of #UD dumps the registers and panics
+    unsafe { asm!("ud2") };
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]\n");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use haribote::{exit_qemu, serial_print, serial_println, task, timer, QemuExitCode};
+
+entry_point!(main);
+
+/// A task overflowing its kernel stack hits the guard page below the stack, and it is terminated
+/// by the double fault handler while the other tasks keep running.
+fn main(boot_info: &'static BootInfo) -> ! {
+    haribote::init(boot_info);
+    serial_print!("task_stack_overflow::overflowing_task_is_terminated...\t");
+
+    task::init();
+    let id = task::allocate(overflow_main).unwrap();
+    task::run(id, Some(0), 0).unwrap();
+
+    let deadline = timer::uptime() + Duration::from_secs(5);
+    while task::can_exit(id) {
+        if timer::uptime() > deadline {
+            serial_println!("[task was not terminated]");
+            exit_qemu(QemuExitCode::Failed);
+            haribote::hlt_loop();
+        }
+        x86_64::instructions::hlt();
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    haribote::hlt_loop();
+}
+
+fn overflow_main() -> ! {
+    stack_overflow();
+    loop {}
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read(); // prevent tail call optimization
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    haribote::test_panic_handler(info)
+}
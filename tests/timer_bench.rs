@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(haribote::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+pub fn main(boot_info: &'static BootInfo) -> ! {
+    use haribote::{allocator, memory};
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initalization failed");
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    haribote::test_panic_handler(info)
+}
+
+use alloc::{boxed::Box, vec::Vec};
+use core::arch::x86_64::_rdtsc;
+use haribote::fifo::{Event, FIFO};
+use haribote::serial_print;
+use haribote::timer::TIMERCTL;
+use spin::Mutex;
+
+/// Returns a FIFO large enough to receive all timeouts of a benchmark.
+fn bench_fifo() -> &'static Mutex<FIFO<Event>> {
+    Box::leak(Box::new(Mutex::new(FIFO::new(1024))))
+}
+
+/// Allocate all timers of `timer_control` and arm them with pseudo-random wait times.
+/// Returns the index of the timers and the total cycles spent in `set_time`.
+fn arm_all(timer_control: &mut TIMERCTL, fifo: &'static Mutex<FIFO<Event>>) -> (Vec<usize>, u64) {
+    let mut ids = Vec::new();
+    let mut cycles = 0;
+    let mut seed: u32 = 12345;
+    while let Some(id) = timer_control.allocate() {
+        timer_control.init(id, fifo, id as u32);
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let wait = 1 + (seed >> 16) % 1000;
+        let start = unsafe { _rdtsc() };
+        timer_control.set_time(id, wait);
+        cycles += unsafe { _rdtsc() } - start;
+        ids.push(id);
+    }
+    (ids, cycles)
+}
+
+#[test_case]
+fn bench_arm() {
+    let fifo = bench_fifo();
+    let mut timer_control = TIMERCTL::new();
+    let (ids, cycles) = arm_all(&mut timer_control, fifo);
+    serial_print!(
+        "arm {} timers: {} cycles/timer ",
+        ids.len(),
+        cycles / ids.len() as u64
+    );
+    assert_eq!(timer_control.queued().count(), ids.len());
+}
+
+#[test_case]
+fn bench_fire() {
+    let fifo = bench_fifo();
+    let mut timer_control = TIMERCTL::new();
+    let (ids, _) = arm_all(&mut timer_control, fifo);
+    let mut cycles = 0;
+    for _ in 0..1000 {
+        timer_control.count += 1;
+        let start = unsafe { _rdtsc() };
+        timer_control.shift_timers();
+        cycles += unsafe { _rdtsc() } - start;
+    }
+    serial_print!(
+        "fire {} timers: {} cycles/timer ",
+        ids.len(),
+        cycles / ids.len() as u64
+    );
+    assert_eq!(timer_control.queued().count(), 0);
+    assert_eq!(fifo.lock().stats().pushes, ids.len() as u64);
+}